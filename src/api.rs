@@ -0,0 +1,678 @@
+use crate::{metrics, Config};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header::RETRY_AFTER,
+    StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Longest a `Retry-After` header can make a request wait.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Start of the line `add_reminder_note` adds to an appointment's notes.
+const REMINDER_NOTE_PREFIX: &str = "Reminder sent on ";
+
+/// Upper limit on the pages fetched from a single endpoint, in case the API keeps
+/// returning full pages.
+const MAX_PAGES: u32 = 1_000;
+
+/// Consecutive API failures for each API root, shared by every request to it.
+static BREAKERS: Mutex<BTreeMap<String, Breaker>> = Mutex::new(BTreeMap::new());
+
+/// A circuit breaker that stops API requests for a while once too many fail in a row.
+///
+/// Once the cooldown is over, the next request is let through; if it fails too, the
+/// breaker opens again right away.
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    /// How much longer requests are paused for, if they are.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.open_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Record the result of a request, logging when the breaker opens or closes.
+    fn record(&mut self, succeeded: bool, config: &Config, now: Instant) {
+        let threshold = config.api_breaker_failures;
+        if succeeded {
+            if threshold > 0 && self.failures >= threshold {
+                info!("API requests are succeeding again, resuming them");
+            }
+            self.failures = 0;
+            self.open_until = None;
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if threshold == 0 || self.failures < threshold {
+            return;
+        }
+        let cooldown = Duration::from_secs(config.api_breaker_cooldown_seconds);
+        if self.failures == threshold {
+            warn!(
+                "{threshold} API requests failed in a row, pausing them for {}",
+                humantime::format_duration(cooldown)
+            );
+        } else {
+            debug!("API request failed after the pause, pausing again");
+        }
+        self.open_until = Some(now + cooldown);
+    }
+}
+
+/// A single appointments's information.
+///
+/// There are additional fields in the API that aren't useful here.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Appointment {
+    pub id: u32,
+    pub start: String,
+    pub end: Option<String>,
+    pub customer_id: u32,
+    pub service_id: Option<u32>,
+    pub provider_id: Option<u32>,
+    pub status: Option<String>,
+    /// Where the appointment is, such as a branch's address.
+    pub location: Option<String>,
+    /// Notes on the appointment, like instructions to prepare for it.
+    pub notes: Option<String>,
+    /// Name of the appointment's service, filled in by `add_names`.
+    #[serde(skip)]
+    pub service_name: Option<String>,
+    /// Name of the appointment's provider, filled in by `add_names`.
+    #[serde(skip)]
+    pub provider_name: Option<String>,
+}
+
+impl Appointment {
+    /// Parse the `String` timestamp into a `chrono::DateTime` struct.
+    ///
+    /// If the timestamp has an offset, it's used; otherwise the timestamp is
+    /// interpreted in the supplied timezone, as older versions of the API don't
+    /// include one. Times that are ambiguous from a DST change resolve to the
+    /// earlier instant.
+    pub fn start_date(&self, timezone: Tz) -> Result<DateTime<Utc>> {
+        parse_timestamp(&self.start, timezone)
+    }
+
+    /// Parse the end timestamp the same way as `start_date`, if the API returned one.
+    pub fn end_date(&self, timezone: Tz) -> Result<Option<DateTime<Utc>>> {
+        self.end
+            .as_deref()
+            .map(|end| parse_timestamp(end, timezone))
+            .transpose()
+    }
+
+    /// The appointment's location, if it has one that isn't blank.
+    pub fn location(&self) -> Option<&str> {
+        self.location
+            .as_deref()
+            .map(str::trim)
+            .filter(|location| !location.is_empty())
+    }
+
+    /// The appointment's notes without the lines added by `add_reminder_note`, if there
+    /// are any left.
+    pub fn customer_notes(&self) -> Option<String> {
+        let notes = self
+            .notes
+            .as_deref()?
+            .lines()
+            .filter(|line| !line.starts_with(REMINDER_NOTE_PREFIX))
+            .join("\n");
+        let notes = notes.trim();
+        (!notes.is_empty()).then(|| notes.to_owned())
+    }
+
+    /// The start time to show customers.
+    ///
+    /// If a strftime `format` is given, the start is formatted with it in the supplied
+    /// timezone; otherwise the timestamp from the API is used as-is.
+    pub fn display_start(&self, timezone: Tz, format: Option<&str>) -> Result<String> {
+        match format {
+            Some(format) => Ok(self
+                .start_date(timezone)?
+                .with_timezone(&timezone)
+                .format(format)
+                .to_string()),
+            None => Ok(self.start.clone()),
+        }
+    }
+
+    /// The end time to show customers, formatted the same way as `display_start`.
+    pub fn display_end(&self, timezone: Tz, format: Option<&str>) -> Result<Option<String>> {
+        match (format, self.end_date(timezone)?) {
+            (Some(format), Some(end)) => Ok(Some(
+                end.with_timezone(&timezone).format(format).to_string(),
+            )),
+            _ => Ok(self.end.clone()),
+        }
+    }
+}
+
+/// Parse a timestamp from the API.
+///
+/// Newer versions of Easy!Appointments return RFC 3339 timestamps with an offset, which is
+/// used as-is. Older versions leave it out, so those timestamps are interpreted in `timezone`.
+pub(crate) fn parse_timestamp(timestamp: &str, timezone: Tz) -> Result<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S"))?;
+    match timezone.from_local_datetime(&naive).earliest() {
+        Some(t) => Ok(t.with_timezone(&Utc)),
+        None => Err(anyhow!("Could not parse datetime")),
+    }
+}
+
+/// A single customer's information.
+///
+/// There are additional fields in the API that aren't useful here.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomerInfo {
+    pub id: u32,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub phone: Option<String>,
+    /// The rest of the customer's fields, like `customField1`, by their API name.
+    #[serde(flatten)]
+    pub fields: HashMap<String, serde_json::Value>,
+}
+
+/// A single service's information.
+///
+/// There are additional fields in the API that aren't useful here.
+#[derive(Debug, Deserialize)]
+pub struct Service {
+    pub id: u32,
+    pub name: String,
+}
+
+/// A single provider's information.
+///
+/// There are additional fields in the API that aren't useful here.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Provider {
+    pub id: u32,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+/// Customers from the API, kept between checks for up to `customer_cache_seconds`.
+#[derive(Debug, Default)]
+pub struct CustomerCache {
+    customers: HashMap<u32, CustomerInfo>,
+    fetched_at: Option<DateTime<Utc>>,
+}
+
+impl CustomerCache {
+    /// Get the customers, fetching them from the API if the cache is stale.
+    ///
+    /// The cache is also refreshed if any of `needed` aren't in it, so new customers
+    /// are picked up right away.
+    pub fn get(
+        &mut self,
+        client: &Client,
+        config: &Config,
+        needed: impl IntoIterator<Item = u32>,
+        now: DateTime<Utc>,
+    ) -> Result<&HashMap<u32, CustomerInfo>> {
+        let fresh = self.fetched_at.is_some_and(|fetched_at| {
+            now - fetched_at < TimeDelta::seconds(config.customer_cache_seconds as i64)
+        });
+        let complete = || {
+            needed
+                .into_iter()
+                .all(|id| self.customers.contains_key(&id))
+        };
+        if !fresh || !complete() {
+            self.customers = get_customers(client, config)?
+                .into_iter()
+                .map(|c| (c.id, c))
+                .collect();
+            self.fetched_at = Some(now);
+            warn_duplicate_emails(&self.customers);
+        } else {
+            debug!("Using {} cached customers", self.customers.len());
+        }
+        Ok(&self.customers)
+    }
+}
+
+/// Warn about customer records that share an email, which are likely the same person.
+fn warn_duplicate_emails(customers: &HashMap<u32, CustomerInfo>) {
+    let by_email = customers
+        .values()
+        .filter(|customer| !customer.email.trim().is_empty())
+        .into_group_map_by(|customer| customer.email.trim().to_lowercase());
+    for (email, duplicates) in by_email.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+        if duplicates.len() > 1 {
+            warn!(
+                "Customers {} share the email {email}",
+                duplicates.iter().map(|c| c.id).sorted().join(", ")
+            );
+        }
+    }
+}
+
+/// Get appointments from the API.
+///
+/// If `api_start_after_param` or `api_start_before_param` are set, only appointments from
+/// `reminder_retention_days` ago to the largest reminder offset from `now` are requested.
+pub fn get_appointments(
+    client: &Client,
+    config: &Config,
+    now: DateTime<Utc>,
+) -> Result<Vec<Appointment>> {
+    let format = |time: DateTime<Utc>| {
+        time.with_timezone(&config.timezone)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    let window = config
+        .reminder_offsets()
+        .into_iter()
+        .max()
+        .unwrap_or_default();
+    let mut filters = Vec::new();
+    if let Some(param) = &config.api_start_after_param {
+        let after = now - TimeDelta::days(config.reminder_retention_days);
+        filters.push((param.as_str(), format(after)));
+    }
+    if let Some(param) = &config.api_start_before_param {
+        let before = now + TimeDelta::from_std(window)?;
+        filters.push((param.as_str(), format(before)));
+    }
+    get_all(client, config, "appointments", &filters)
+}
+
+/// Request a single appointment from the API, to check that it can be reached.
+pub fn ping(client: &Client, config: &Config) -> Result<()> {
+    get::<Vec<Appointment>>(client, config, "appointments", &[("length", "1")])?;
+    Ok(())
+}
+
+/// Get a single appointment from the API.
+pub fn get_appointment(client: &Client, config: &Config, id: u32) -> Result<Appointment> {
+    get(client, config, &format!("appointments/{id}"), &[])
+        .with_context(|| format!("Could not get appointment #{id}"))
+}
+
+/// Get a single customer from the API.
+pub fn get_customer(client: &Client, config: &Config, id: u32) -> Result<CustomerInfo> {
+    get(client, config, &format!("customers/{id}"), &[])
+        .with_context(|| format!("Could not get customer {id}"))
+}
+
+/// Append a "Reminder sent on <date>" line to the appointment's notes in Easy!Appointments.
+pub fn add_reminder_note(
+    client: &Client,
+    config: &Config,
+    appointment: &Appointment,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let line = format!(
+        "{REMINDER_NOTE_PREFIX}{}",
+        now.with_timezone(&config.timezone).format("%Y-%m-%d")
+    );
+    let notes = match appointment.notes.as_deref().map(str::trim_end) {
+        Some(notes) if !notes.is_empty() => format!("{notes}\n{line}"),
+        _ => line,
+    };
+    let endpoint = format!("appointments/{}", appointment.id);
+    let result = authorize(client.put(format!("{}{endpoint}", config.api_root)), config)
+        .json(&serde_json::json!({ "notes": notes }))
+        .send()
+        .map_err(anyhow::Error::from)
+        .and_then(|resp| {
+            if resp.status().is_success() {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "Got status {} from {endpoint} API",
+                    resp.status().as_u16()
+                ))
+            }
+        });
+    if result.is_err() {
+        metrics::API_ERRORS.inc();
+    }
+    result
+}
+
+/// Get customers from the API.
+pub fn get_customers(client: &Client, config: &Config) -> Result<Vec<CustomerInfo>> {
+    get_all(client, config, "customers", &[])
+}
+
+/// Get services from the API.
+pub fn get_services(client: &Client, config: &Config) -> Result<Vec<Service>> {
+    get_all(client, config, "services", &[])
+}
+
+/// Get providers from the API.
+pub fn get_providers(client: &Client, config: &Config) -> Result<Vec<Provider>> {
+    get_all(client, config, "providers", &[])
+}
+
+/// Fill in the service and provider names of the appointments.
+///
+/// Services and providers are only fetched if a template uses their names. Names that
+/// can't be found are left empty, with a warning.
+pub fn add_names(client: &Client, config: &Config, appointments: &mut [Appointment]) -> Result<()> {
+    if config.uses_placeholder("SERVICE_NAME") {
+        let services: HashMap<u32, String> = get_services(client, config)?
+            .into_iter()
+            .map(|service| (service.id, service.name))
+            .collect();
+        for appointment in appointments.iter_mut() {
+            appointment.service_name = lookup_name(&services, appointment.service_id, "service");
+        }
+    }
+    if config.uses_placeholder("PROVIDER_NAME") {
+        let providers: HashMap<u32, String> = get_providers(client, config)?
+            .into_iter()
+            .map(|provider| {
+                (
+                    provider.id,
+                    format!("{} {}", provider.first_name, provider.last_name),
+                )
+            })
+            .collect();
+        for appointment in appointments.iter_mut() {
+            appointment.provider_name =
+                lookup_name(&providers, appointment.provider_id, "provider");
+        }
+    }
+    Ok(())
+}
+
+/// Look up a name by ID, warning if it can't be found.
+fn lookup_name(names: &HashMap<u32, String>, id: Option<u32>, kind: &str) -> Option<String> {
+    let name = id.and_then(|id| names.get(&id));
+    if name.is_none() {
+        match id {
+            Some(id) => warn!("Could not find {kind} {id}"),
+            None => warn!("Appointment has no {kind}"),
+        }
+    }
+    name.cloned()
+}
+
+/// Something returned by a paginated API endpoint, with an ID.
+trait Item: DeserializeOwned {
+    fn id(&self) -> u32;
+}
+
+impl Item for Appointment {
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Item for CustomerInfo {
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Item for Service {
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Item for Provider {
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Get every page of results from a paginated API endpoint.
+///
+/// Pages of `page_size` are requested until one comes back short or empty. The `filters`
+/// are added to the query of each one.
+///
+/// A page with more than `page_size` results, or the same ones as the page before, means
+/// the API is ignoring the pagination, so that's an error rather than reading every page.
+fn get_all<T: Item>(
+    client: &Client,
+    config: &Config,
+    endpoint: &str,
+    filters: &[(&str, String)],
+) -> Result<Vec<T>> {
+    let length = config.page_size.to_string();
+    let mut results = Vec::new();
+    let mut previous = Vec::new();
+    for page in 1..=MAX_PAGES {
+        let page = page.to_string();
+        let mut query = vec![("page", page.as_str()), ("length", length.as_str())];
+        query.extend(filters.iter().map(|(name, value)| (*name, value.as_str())));
+        let items: Vec<T> = get(client, config, endpoint, &query)?;
+        let count = items.len();
+        if count > config.page_size as usize {
+            return Err(anyhow!(
+                "The {endpoint} API returned {count} results for page {page} of {length}, \
+                so it doesn't seem to support pagination"
+            ));
+        }
+        let ids = items.iter().map(Item::id).collect_vec();
+        if !ids.is_empty() && ids == previous {
+            return Err(anyhow!(
+                "The {endpoint} API returned the same results for page {page} as the page \
+                before, so it doesn't seem to support pagination"
+            ));
+        }
+        previous = ids;
+        results.extend(items);
+        if count < config.page_size as usize {
+            debug!(
+                "Got {} results from {endpoint} API in {page} pages",
+                results.len()
+            );
+            return Ok(results);
+        }
+    }
+    warn!("Stopped reading the {endpoint} API after {MAX_PAGES} pages");
+    Ok(results)
+}
+
+/// Get and deserialize the response from an API endpoint, counting failures in the metrics.
+fn get<T: DeserializeOwned>(
+    client: &Client,
+    config: &Config,
+    endpoint: &str,
+    query: &[(&str, &str)],
+) -> Result<T> {
+    let remaining = BREAKERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&config.api_root)
+        .and_then(|breaker| breaker.remaining(Instant::now()));
+    if let Some(remaining) = remaining {
+        return Err(anyhow!(
+            "Not calling the {endpoint} API for another {} after repeated failures",
+            humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+        ));
+    }
+    let result = try_get(client, config, endpoint, query);
+    if result.is_err() {
+        metrics::API_ERRORS.inc();
+    }
+    BREAKERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(config.api_root.clone())
+        .or_default()
+        .record(result.is_ok(), config, Instant::now());
+    result
+}
+
+/// Get and deserialize the response from an API endpoint.
+fn try_get<T: DeserializeOwned>(
+    client: &Client,
+    config: &Config,
+    endpoint: &str,
+    query: &[(&str, &str)],
+) -> Result<T> {
+    let resp = send_with_retry(client, config, endpoint, query)?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Got status {} from {endpoint} API",
+            resp.status().as_u16()
+        ));
+    }
+    let data = resp.json()?;
+    Ok(data)
+}
+
+/// Send a request to an API endpoint.
+///
+/// Network errors, 5xx responses, and 429 responses are retried up to `api_max_retries`
+/// times, doubling the delay between each attempt. A 429 waits for as long as its
+/// `Retry-After` header asks instead, up to `MAX_RETRY_AFTER`. Other responses are
+/// returned as-is.
+fn send_with_retry(
+    client: &Client,
+    config: &Config,
+    endpoint: &str,
+    query: &[(&str, &str)],
+) -> Result<Response> {
+    let mut delay = Duration::from_millis(config.api_retry_base_ms);
+    let mut retries = 0;
+    loop {
+        let result = authorize(
+            client
+                .get(format!("{}{endpoint}", config.api_root))
+                .query(query),
+            config,
+        )
+        .send();
+        let mut wait = delay;
+        let reason = match &result {
+            Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => {
+                if let Some(retry_after) = retry_after(resp) {
+                    wait = retry_after.min(MAX_RETRY_AFTER);
+                }
+                String::from("rate limited")
+            }
+            Ok(resp) if resp.status().is_server_error() => {
+                format!("status {}", resp.status().as_u16())
+            }
+            Ok(_) => return Ok(result?),
+            Err(e) => e.to_string(),
+        };
+        if retries >= config.api_max_retries {
+            return Ok(result?);
+        }
+        retries += 1;
+        debug!(
+            "Request to {endpoint} API failed ({reason}), retry {retries} of {} in {}ms",
+            config.api_max_retries,
+            wait.as_millis()
+        );
+        sleep(wait);
+        delay *= 2;
+    }
+}
+
+/// Add the configured API key, and basic auth if there is any, to a request.
+fn authorize(request: RequestBuilder, config: &Config) -> RequestBuilder {
+    let request = match &config.api_basic_user {
+        Some(user) => request.basic_auth(user, config.api_basic_pass.as_ref()),
+        None => request,
+    };
+    if config.api_auth_header.eq_ignore_ascii_case("bearer") {
+        request.bearer_auth(&config.api_key)
+    } else {
+        request.header(config.api_auth_header.as_str(), &config.api_key)
+    }
+}
+
+/// How long the response's `Retry-After` header asks to wait, in seconds or as an HTTP date.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            Some(
+                (date.with_timezone(&Utc) - Utc::now())
+                    .to_std()
+                    .unwrap_or_default(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let config = Config::test_with(
+            r#"
+            api_breaker_failures = 2
+            api_breaker_cooldown_seconds = 60
+            "#,
+        );
+        let now = Instant::now();
+        let mut breaker = Breaker {
+            failures: 0,
+            open_until: None,
+        };
+        breaker.record(false, &config, now);
+        assert_eq!(breaker.remaining(now), None);
+        breaker.record(false, &config, now);
+        assert_eq!(breaker.remaining(now), Some(Duration::from_secs(60)));
+        let later = now + Duration::from_secs(61);
+        assert_eq!(breaker.remaining(later), None);
+        breaker.record(false, &config, later);
+        assert_eq!(breaker.remaining(later), Some(Duration::from_secs(60)));
+        breaker.record(true, &config, later);
+        assert_eq!(breaker.remaining(later), None);
+        assert_eq!(breaker.failures, 0);
+    }
+
+    #[test]
+    fn parses_timestamps_with_and_without_offsets() {
+        let timezone: Tz = "America/New_York".parse().unwrap();
+        let expected = Utc.with_ymd_and_hms(2024, 1, 2, 20, 4, 5).unwrap();
+        for timestamp in [
+            "2024-01-02 15:04:05",
+            "2024-01-02T15:04:05",
+            "2024-01-02T20:04:05+00:00",
+            "2024-01-02T21:04:05+01:00",
+            "2024-01-02T20:04:05Z",
+        ] {
+            assert_eq!(
+                parse_timestamp(timestamp, timezone).unwrap(),
+                expected,
+                "{timestamp}"
+            );
+        }
+        assert!(parse_timestamp("tomorrow", timezone).is_err());
+    }
+}
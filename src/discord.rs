@@ -0,0 +1,104 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    config::Config,
+    notify::Notifier,
+    template::AppointmentValues,
+};
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+
+/// Most embeds Discord accepts in a single message.
+const MAX_EMBEDS: usize = 10;
+
+/// Post embeds to a Discord webhook, treating any non-2xx response as a failure.
+pub fn send_discord(client: &Client, url: &str, embeds: &[Value]) -> Result<()> {
+    let resp = client.post(url).json(&json!({ "embeds": embeds })).send()?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Got status {} from Discord webhook",
+            resp.status().as_u16()
+        ));
+    }
+    debug!("Discord notification sent");
+    Ok(())
+}
+
+/// Build the embed describing an appointment for staff.
+fn embed(customer: &CustomerInfo, appointment: &Appointment, config: &Config) -> Result<Value> {
+    let values = AppointmentValues::new(appointment, customer, config)?;
+    let mut fields = vec![
+        json!({
+            "name": "Customer",
+            "value": format!("{} {}", customer.first_name, customer.last_name),
+            "inline": true,
+        }),
+        json!({ "name": "Time", "value": values.datetime, "inline": true }),
+    ];
+    for (name, value) in [
+        ("Service", &values.service_name),
+        ("Provider", &values.provider_name),
+    ] {
+        if !value.is_empty() {
+            fields.push(json!({ "name": name, "value": value, "inline": true }));
+        }
+    }
+    Ok(json!({
+        "title": format!("Upcoming appointment #{}", appointment.id),
+        "timestamp": appointment.start_date(config.timezone)?.to_rfc3339(),
+        "fields": fields,
+    }))
+}
+
+/// Posts each reminder to a Discord channel as an embed, such as for front-desk staff.
+pub struct DiscordNotifier<'a> {
+    client: &'a Client,
+    config: &'a Config,
+    url: &'a str,
+    dry_run: bool,
+}
+
+impl<'a> DiscordNotifier<'a> {
+    /// If `dry_run` is set, messages are logged instead of posted.
+    pub fn new(client: &'a Client, config: &'a Config, url: &'a str, dry_run: bool) -> Self {
+        Self {
+            client,
+            config,
+            url,
+            dry_run,
+        }
+    }
+}
+
+impl Notifier for DiscordNotifier<'_> {
+    fn name(&self) -> &'static str {
+        "Discord"
+    }
+
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+        self.notify_many(customer, &[appointment])
+    }
+
+    /// Post the appointments as embeds in as few messages as Discord allows.
+    fn notify_many(&self, customer: &CustomerInfo, appointments: &[&Appointment]) -> Result<()> {
+        let embeds = appointments
+            .iter()
+            .map(|appointment| embed(customer, appointment, self.config))
+            .collect::<Result<Vec<_>>>()?;
+        if self.dry_run {
+            info!(
+                "Dry run: would post {} appointment(s) to Discord",
+                embeds.len()
+            );
+            return Ok(());
+        }
+        embeds
+            .chunks(MAX_EMBEDS)
+            .try_for_each(|chunk| send_discord(self.client, self.url, chunk))
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+}
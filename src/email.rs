@@ -0,0 +1,451 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    config::{Config, SmtpTls, TemplateEngine},
+    ics, metrics,
+    notify::Notifier,
+    template::{
+        render_handlebars, render_html_template, render_template, strip_html, AppointmentValues,
+        TemplateContext,
+    },
+    unsubscribe,
+};
+use anyhow::{anyhow, Result};
+use chrono::TimeDelta;
+use lettre::{
+    message::{dkim::DkimConfig, header::ContentType, Attachment, Mailbox, MultiPart, SinglePart},
+    transport::smtp::{authentication::Credentials, response::Response},
+    Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use log::{debug, info};
+use std::{
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use tokio::runtime::{self, Runtime};
+
+/// Sends reminders by email, through the configured SMTP server.
+pub struct EmailNotifier<'a> {
+    config: &'a Config,
+    mailer: &'a Mailer,
+    dry_run: bool,
+    /// When the last email was sent, for `email_rate_per_minute`.
+    last_sent: Mutex<Option<Instant>>,
+}
+
+impl<'a> EmailNotifier<'a> {
+    /// If `dry_run` is set, emails are logged instead of sent.
+    pub fn new(config: &'a Config, mailer: &'a Mailer, dry_run: bool) -> Self {
+        Self {
+            config,
+            mailer,
+            dry_run,
+            last_sent: Mutex::new(None),
+        }
+    }
+
+    /// Wait until the next email can be sent without going over `email_rate_per_minute`.
+    fn throttle(&self) {
+        let Some(rate) = self.config.email_rate_per_minute.filter(|_| !self.dry_run) else {
+            return;
+        };
+        let spacing = Duration::from_secs(60) / rate.max(1);
+        // hold the lock while waiting, so concurrent sends queue up behind each other
+        let mut last_sent = self.last_sent.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(wait) = last_sent.and_then(|last| spacing.checked_sub(last.elapsed())) {
+            debug!(
+                "Waiting {}ms to stay under the email rate limit",
+                wait.as_millis()
+            );
+            sleep(wait);
+        }
+        *last_sent = Some(Instant::now());
+    }
+}
+
+impl Notifier for EmailNotifier<'_> {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+        self.throttle();
+        send_notification(
+            customer,
+            appointment,
+            self.config,
+            self.mailer,
+            self.dry_run,
+        )
+        .inspect_err(|_| metrics::EMAILS_FAILED.inc())
+    }
+
+    /// Send a single digest email listing all of the appointments.
+    fn notify_many(&self, customer: &CustomerInfo, appointments: &[&Appointment]) -> Result<()> {
+        self.throttle();
+        send_digest(
+            customer,
+            appointments,
+            self.config,
+            self.mailer,
+            self.dry_run,
+        )
+        .inspect_err(|_| metrics::EMAILS_FAILED.inc())
+    }
+}
+
+/// A rendered reminder email.
+pub struct RenderedEmail {
+    pub subject: String,
+    /// HTML if `email_body_html` is set.
+    pub body: String,
+    /// Plain text version of the body.
+    pub text: String,
+}
+
+/// Render one of the email templates with the configured `template_engine`.
+///
+/// `appointments` is only used by Handlebars templates in digest emails.
+fn render(
+    config: &Config,
+    template: &str,
+    customer_info: &CustomerInfo,
+    values: &AppointmentValues,
+    appointments: &[AppointmentValues],
+    html: bool,
+) -> Result<String> {
+    match config.template_engine {
+        TemplateEngine::Placeholders if html => {
+            Ok(render_html_template(template, customer_info, values))
+        }
+        TemplateEngine::Placeholders => Ok(render_template(template, customer_info, values)),
+        TemplateEngine::Handlebars => render_handlebars(
+            template,
+            &TemplateContext::new(customer_info, values, appointments),
+            html,
+        ),
+    }
+}
+
+/// Render the reminder email for the appointment, in the customer's language if
+/// there are templates for it.
+pub fn render_email(
+    customer_info: &CustomerInfo,
+    appointment: &Appointment,
+    config: &Config,
+) -> Result<RenderedEmail> {
+    let values = AppointmentValues::new(appointment, customer_info, config)?;
+    let html = config.email_body_html;
+    let (subject, body, body_text) = match config.language_templates(customer_info) {
+        Some(templates) => (
+            &templates.email_subject,
+            &templates.email_body,
+            &templates.email_body_text,
+        ),
+        None => (
+            &config.email_subject,
+            &config.email_body,
+            &config.email_body_text,
+        ),
+    };
+    let body = render(config, body, customer_info, &values, &[], html)?;
+    let text = match (html, body_text) {
+        (false, _) => body.clone(),
+        (true, Some(template)) => render(config, template, customer_info, &values, &[], false)?,
+        (true, None) => strip_html(&body),
+    };
+    Ok(RenderedEmail {
+        subject: render(config, subject, customer_info, &values, &[], false)?,
+        body,
+        text,
+    })
+}
+
+/// Whether the email address can be sent to.
+pub fn is_valid_address(email: &str) -> bool {
+    email.parse::<Address>().is_ok()
+}
+
+/// Send an email to the customer to remind them of the upcoming appointment.
+///
+/// If `dry_run` is set, the email is logged instead of sent.
+fn send_notification(
+    customer_info: &CustomerInfo,
+    appointment: &Appointment,
+    config: &Config,
+    mailer: &Mailer,
+    dry_run: bool,
+) -> Result<()> {
+    let email = render_email(customer_info, appointment, config)?;
+    let invite = if config.ics {
+        let start = appointment.start_date(config.timezone)?;
+        let from: Mailbox = config.email_from.parse()?;
+        let to: Mailbox = customer_info.email.parse()?;
+        let invite = ics::build_invite(&ics::Invite {
+            appointment_id: appointment.id,
+            start,
+            end: appointment
+                .end_date(config.timezone)?
+                .unwrap_or(start + TimeDelta::minutes(config.ics_duration_minutes)),
+            summary: &email.subject,
+            description: &email.text,
+            location: appointment.location(),
+            organizer: from.email.as_ref(),
+            attendee: to.email.as_ref(),
+        });
+        Some(Attachment::new(String::from("invite.ics")).body(
+            invite,
+            ContentType::parse("text/calendar; charset=utf-8; method=REQUEST")?,
+        ))
+    } else {
+        None
+    };
+    send_email(customer_info, email, invite, config, mailer, dry_run)
+}
+
+/// Send a single email to the customer listing all of their upcoming appointments.
+///
+/// Each appointment is rendered with `email_digest_item`, and the results are joined
+/// into the `%APPOINTMENTS%` placeholder of `email_digest_body`. Handlebars templates
+/// can also loop over `appointments` themselves.
+fn send_digest(
+    customer_info: &CustomerInfo,
+    appointments: &[&Appointment],
+    config: &Config,
+    mailer: &Mailer,
+    dry_run: bool,
+) -> Result<()> {
+    let html = config.email_body_html;
+    let values = appointments
+        .iter()
+        .map(|appointment| AppointmentValues::new(appointment, customer_info, config))
+        .collect::<Result<Vec<_>>>()?;
+    let items = values
+        .iter()
+        .map(|values| {
+            render(
+                config,
+                &config.email_digest_item,
+                customer_info,
+                values,
+                &[],
+                html,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?
+        .join("");
+    // for the placeholders that aren't about a single appointment
+    let empty = AppointmentValues {
+        unsubscribe_url: unsubscribe::unsubscribe_url(config, &customer_info.email),
+        ..AppointmentValues::default()
+    };
+    let body = render(
+        config,
+        &config.email_digest_body,
+        customer_info,
+        &empty,
+        &values,
+        html,
+    )?
+    .replace("%APPOINTMENTS%", &items);
+    let text = if html {
+        strip_html(&body)
+    } else {
+        body.clone()
+    };
+    let email = RenderedEmail {
+        subject: render(
+            config,
+            &config.email_subject,
+            customer_info,
+            &empty,
+            &values,
+            false,
+        )?,
+        body,
+        text,
+    };
+    send_email(customer_info, email, None, config, mailer, dry_run)
+}
+
+/// Build and send a reminder email to the customer.
+///
+/// If `email_body_html` is set, the plain text version is sent alongside the HTML body.
+/// A negative response from the server is returned as an error.
+///
+/// If `dry_run` is set, the email is logged instead of sent.
+fn send_email(
+    customer_info: &CustomerInfo,
+    email: RenderedEmail,
+    attachment: Option<SinglePart>,
+    config: &Config,
+    mailer: &Mailer,
+    dry_run: bool,
+) -> Result<()> {
+    let RenderedEmail {
+        subject,
+        body,
+        text,
+    } = email;
+    let mut builder = Message::builder()
+        .from(config.email_from.parse()?)
+        .reply_to(config.email_reply_to.parse()?)
+        .to(customer_info.email.parse()?)
+        .subject(&subject);
+    for address in &config.email_cc {
+        builder = builder.cc(address.parse()?);
+    }
+    let alternative = config
+        .email_body_html
+        .then(|| MultiPart::alternative_plain_html(text.clone(), body.clone()));
+    let email = if let Some(attachment) = attachment {
+        let content = match alternative {
+            Some(alternative) => MultiPart::mixed().multipart(alternative),
+            None => MultiPart::mixed().singlepart(SinglePart::plain(body)),
+        };
+        builder.multipart(content.singlepart(attachment))?
+    } else if let Some(alternative) = alternative {
+        builder.multipart(alternative)?
+    } else {
+        builder.body(body)?
+    };
+
+    if dry_run {
+        info!(
+            "Dry run: would send email to {} with subject \"{}\":\n{}",
+            customer_info.email, subject, text
+        );
+        return Ok(());
+    }
+
+    let result = mailer.send(email)?;
+    if !result.is_positive() {
+        return Err(anyhow!(
+            "Got response code {} from the SMTP server sending to {}",
+            result.code(),
+            customer_info.email
+        ));
+    }
+    debug!("Email notification sent");
+    Ok(())
+}
+
+/// Send a fixed test message to `to`, to check the SMTP settings.
+pub fn send_test_email(mailer: &Mailer, config: &Config, to: &str) -> Result<()> {
+    send_plain_email(
+        mailer,
+        config,
+        to,
+        "Test email from ea-appointment-reminders",
+        String::from(
+            "This is a test email from ea-appointment-reminders. \
+            If you can read this, the SMTP settings are working.",
+        ),
+    )
+}
+
+/// Send a plain text email that isn't a reminder, such as to staff.
+///
+/// A negative response from the server is returned as an error.
+pub fn send_plain_email(
+    mailer: &Mailer,
+    config: &Config,
+    to: &str,
+    subject: &str,
+    body: String,
+) -> Result<()> {
+    let email = Message::builder()
+        .from(config.email_from.parse()?)
+        .reply_to(config.email_reply_to.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .body(body)?;
+    let result = mailer.send(email)?;
+    if !result.is_positive() {
+        return Err(anyhow!(
+            "Got response code {} from the SMTP server",
+            result.code()
+        ));
+    }
+    Ok(())
+}
+
+/// The SMTP transport and DKIM settings, built once and shared by every email.
+///
+/// The transport keeps a pool of connections to the server, so they're reused
+/// across emails rather than set up again for each one. It's async, and runs on
+/// its own runtime so a slow server doesn't hold up a thread per send.
+pub struct Mailer {
+    /// Only `None` while being dropped.
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    dkim: Option<DkimConfig>,
+    runtime: Runtime,
+}
+
+impl Mailer {
+    /// Build the transport and read the DKIM key, which fails if either can't be used.
+    pub fn new(config: &Config) -> Result<Self> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("smtp")
+            .enable_all()
+            .build()?;
+        // the connection pool spawns its tasks on the runtime it's built in
+        let transport = {
+            let _guard = runtime.enter();
+            smtp_transport(config)?
+        };
+        Ok(Self {
+            transport: Some(transport),
+            dkim: config.dkim()?,
+            runtime,
+        })
+    }
+
+    /// Connect and log in to the SMTP server, without sending anything.
+    pub fn test_connection(&self) -> Result<()> {
+        if !self.runtime.block_on(self.transport().test_connection())? {
+            return Err(anyhow!("The SMTP server did not respond"));
+        }
+        Ok(())
+    }
+
+    /// DKIM-sign the email if that's configured, and send it.
+    fn send(&self, mut email: Message) -> Result<Response> {
+        if let Some(dkim) = &self.dkim {
+            email.sign(dkim);
+        }
+        Ok(self.runtime.block_on(self.transport().send(email))?)
+    }
+
+    fn transport(&self) -> &AsyncSmtpTransport<Tokio1Executor> {
+        self.transport
+            .as_ref()
+            .expect("transport is set until drop")
+    }
+}
+
+impl Drop for Mailer {
+    /// The connection pool spawns a task to close its connections when it's
+    /// dropped, which has to happen inside the runtime.
+    fn drop(&mut self) {
+        let _guard = self.runtime.enter();
+        self.transport.take();
+    }
+}
+
+/// Build the SMTP transport for the configured server and `smtp_tls` mode.
+fn smtp_transport(config: &Config) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    type Transport = AsyncSmtpTransport<Tokio1Executor>;
+    let mut builder = match config.smtp_tls {
+        SmtpTls::Implicit => Transport::relay(&config.smtp_host)?,
+        SmtpTls::Starttls => Transport::starttls_relay(&config.smtp_host)?,
+        SmtpTls::None => Transport::builder_dangerous(&config.smtp_host),
+    };
+    if let Some(port) = config.smtp_port {
+        builder = builder.port(port);
+    }
+    Ok(builder
+        .credentials(Credentials::from((&config.smtp_user, &config.smtp_pass)))
+        .build())
+}
@@ -0,0 +1,159 @@
+use crate::{metrics, unsubscribe::Unsubscriber, Event};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{debug, error, info};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread,
+};
+use tiny_http::{Header, Method, Response, Server};
+use url::form_urlencoded;
+
+/// Shown for a `GET` of an unsubscribe link, so that it takes a click to unsubscribe.
+const UNSUBSCRIBE_PAGE: &str = "<!DOCTYPE html>
+<html>
+<body>
+<p>Stop getting appointment reminders?</p>
+<form method=\"post\"><button type=\"submit\">Unsubscribe</button></form>
+</body>
+</html>
+";
+
+/// State of the reminder loop, shared with the HTTP server.
+pub struct Status {
+    /// Unix timestamp of when the last check finished.
+    last_check: AtomicI64,
+    /// How long after the last check the loop is still considered alive, in seconds.
+    max_age: i64,
+}
+
+impl Status {
+    /// Create a new status, treating startup as the most recent check.
+    pub fn new(poll_interval: u64) -> Self {
+        Self {
+            last_check: AtomicI64::new(Utc::now().timestamp()),
+            max_age: poll_interval as i64 * 2,
+        }
+    }
+
+    /// Record that a check just finished.
+    pub fn record_check(&self) {
+        self.last_check
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    /// Whether a check finished within the last 2 poll intervals.
+    fn is_healthy(&self) -> bool {
+        Utc::now().timestamp() - self.last_check.load(Ordering::Relaxed) <= self.max_age
+    }
+}
+
+/// Start the HTTP server on a background thread.
+///
+/// `/healthz` returns 200 while the reminder loop is alive, and 503 otherwise.
+/// `/metrics` returns the Prometheus metrics.
+/// A `POST` to `/reload` reloads the config before the next check.
+pub fn serve(port: u16, status: Arc<Status>, events: Sender<Event>) -> Result<()> {
+    listen("0.0.0.0", port, move |method, path, _| match path {
+        "/reload" if *method == Method::Post => {
+            if events.send(Event::Reload).is_ok() {
+                Response::from_string("Reloading").with_status_code(202)
+            } else {
+                Response::from_string("Shutting down").with_status_code(503)
+            }
+        }
+        "/reload" => Response::from_string("Method Not Allowed").with_status_code(405),
+        "/healthz" if status.is_healthy() => Response::from_string("OK"),
+        "/healthz" => Response::from_string("Stale").with_status_code(503),
+        "/metrics" => Response::from_string(metrics::render()).with_header(
+            "Content-Type: text/plain; version=0.0.4"
+                .parse::<Header>()
+                .unwrap(),
+        ),
+        _ => not_found(),
+    })
+}
+
+/// Start the HTTP server for unsubscribe links on a background thread.
+///
+/// It only serves `/unsubscribe`, so it can be exposed to customers without exposing
+/// the other endpoints.
+pub fn serve_unsubscribe(address: &str, port: u16, unsubscriber: Unsubscriber) -> Result<()> {
+    listen(address, port, move |method, path, query| match path {
+        "/unsubscribe" => unsubscribe(&unsubscriber, method, query),
+        _ => not_found(),
+    })
+}
+
+/// Accept requests on `address` and `port` on a background thread, responding with what
+/// `handle` returns for the method, path, and query string of each.
+fn listen(
+    address: &str,
+    port: u16,
+    handle: impl Fn(&Method, &str, &str) -> Response<Cursor<Vec<u8>>> + Send + 'static,
+) -> Result<()> {
+    let server = Server::http((address, port)).map_err(|e| anyhow!(e))?;
+    info!("Serving HTTP on {address} port {port}");
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // the query string isn't logged, since it can have the customer's email address
+            let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+            debug!("HTTP {} {path}", request.method());
+            let response = handle(request.method(), path, query);
+            if let Err(e) = request.respond(response) {
+                error!("Could not send HTTP response: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn not_found() -> Response<Cursor<Vec<u8>>> {
+    Response::from_string("Not Found").with_status_code(404)
+}
+
+/// Handle an unsubscribe link.
+///
+/// A `GET` only shows a button to confirm, so that link scanners in email clients don't
+/// unsubscribe anyone. A `POST`, from that button or a one-click unsubscribe, adds the email
+/// to the suppression file.
+fn unsubscribe(
+    unsubscriber: &Unsubscriber,
+    method: &Method,
+    query: &str,
+) -> Response<Cursor<Vec<u8>>> {
+    let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes()).collect();
+    let email = params
+        .get("email")
+        .map(|email| email.as_ref())
+        .unwrap_or("");
+    let token = params
+        .get("token")
+        .map(|token| token.as_ref())
+        .unwrap_or("");
+    if email.is_empty() || !unsubscriber.is_valid(email, token) {
+        return Response::from_string("This unsubscribe link isn't valid").with_status_code(403);
+    }
+    match method {
+        Method::Get => Response::from_string(UNSUBSCRIBE_PAGE).with_header(
+            "Content-Type: text/html; charset=utf-8"
+                .parse::<Header>()
+                .unwrap(),
+        ),
+        Method::Post => match unsubscriber.unsubscribe(email) {
+            Ok(()) => Response::from_string("You won't get any more appointment reminders"),
+            Err(e) => {
+                error!("Could not unsubscribe {email}: {e}");
+                Response::from_string("Could not unsubscribe, please try again later")
+                    .with_status_code(500)
+            }
+        },
+        _ => Response::from_string("Method Not Allowed").with_status_code(405),
+    }
+}
@@ -0,0 +1,43 @@
+use chrono::{DateTime, Utc};
+use icalendar::{Calendar, Component, Event, EventLike};
+
+/// Details of the calendar event to build an invite for.
+pub struct Invite<'a> {
+    pub appointment_id: u32,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub summary: &'a str,
+    pub description: &'a str,
+    pub location: Option<&'a str>,
+    pub organizer: &'a str,
+    pub attendee: &'a str,
+}
+
+/// Build an iCalendar document containing a single event for the appointment.
+///
+/// The event UID is derived from the appointment ID, so calendar clients replace
+/// an earlier invite for the same appointment rather than adding a duplicate.
+pub fn build_invite(invite: &Invite) -> String {
+    let mut event = Event::new();
+    if let Some(location) = invite.location {
+        event.location(location);
+    }
+    let event = event
+        .uid(&format!(
+            "appointment-{}@ea-appointment-reminders",
+            invite.appointment_id
+        ))
+        .timestamp(Utc::now())
+        .starts(invite.start)
+        .ends(invite.end)
+        .summary(invite.summary)
+        .description(invite.description)
+        .add_property("ORGANIZER", format!("mailto:{}", invite.organizer))
+        .add_multi_property("ATTENDEE", &format!("mailto:{}", invite.attendee))
+        .done();
+    Calendar::new()
+        .append_property(("METHOD", "REQUEST"))
+        .push(event)
+        .done()
+        .to_string()
+}
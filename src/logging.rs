@@ -0,0 +1,266 @@
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clap::ValueEnum;
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    Log, Metadata, Record,
+};
+use pretty_env_logger::env_logger;
+use serde::Deserialize;
+use serde_json::{json, Map};
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Colored, human-readable text.
+    #[default]
+    Pretty,
+    /// One JSON object per line.
+    Json,
+}
+
+/// How much to log, from least to most.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// Debug logs from this app, and info from its dependencies.
+    Debug,
+    /// Trace logs from this app and the HTTP client, and debug from everything else.
+    Trace,
+}
+
+impl LogLevel {
+    /// The level for `-v` given `count` times, starting from info.
+    pub fn from_verbosity(count: u8) -> Self {
+        match count {
+            0 => Self::Info,
+            1 => Self::Debug,
+            _ => Self::Trace,
+        }
+    }
+
+    /// The `RUST_LOG` filters for the level.
+    pub fn filters(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "info,ea_appointment_reminders=debug",
+            Self::Trace => "debug,ea_appointment_reminders=trace,reqwest=trace,hyper_util=trace",
+        }
+    }
+}
+
+/// When to start a new log file.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// At the first log line of each local day.
+    #[default]
+    Daily,
+    /// When the file would grow past `log_max_size_mb`.
+    Size,
+}
+
+/// The log file that lines are copied to, once `log_to_file` has been called.
+static LOG_FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+/// Install the logger, filtered by `RUST_LOG`.
+pub fn init(format: LogFormat) {
+    let mut builder = match format {
+        LogFormat::Pretty => pretty_env_logger::formatted_builder(),
+        LogFormat::Json => {
+            let mut builder = env_logger::Builder::new();
+            builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+            builder
+        }
+    };
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let console = builder.build();
+    log::set_max_level(console.filter());
+    log::set_boxed_logger(Box::new(Logger { console, format }))
+        .expect("The logger was already installed");
+}
+
+/// Also write log lines to `path`, in the same format as the console, rotating it
+/// by `rotation` and keeping `keep` old files next to it.
+pub fn log_to_file(path: &Path, rotation: LogRotation, max_bytes: u64, keep: usize) -> Result<()> {
+    let file = RotatingFile::open(path, rotation, max_bytes, keep)?;
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// Writes to the console, and to the log file if there is one.
+struct Logger {
+    console: env_logger::Logger,
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.console.matches(record) {
+            return;
+        }
+        self.console.log(record);
+        let mut file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(file) = file.as_mut() {
+            let line = match self.format {
+                LogFormat::Pretty => format!(
+                    "{} {:<5} {} > {}",
+                    Utc::now().to_rfc3339(),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ),
+                LogFormat::Json => json_line(record).to_string(),
+            };
+            if let Err(e) = file.write_line(&line) {
+                // the logger can't log its own errors, so this is all that can be done
+                eprintln!("Could not write to log file: {e}");
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+/// Format a record as a JSON object.
+fn json_line(record: &Record) -> serde_json::Value {
+    let mut fields = FieldVisitor(Map::new());
+    // an error here only means fewer contextual fields
+    let _ = record.key_values().visit(&mut fields);
+    json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "fields": fields.0,
+    })
+}
+
+/// Collects a log record's key-value pairs into a JSON object.
+struct FieldVisitor(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for FieldVisitor {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            json!(n)
+        } else if let Some(n) = value.to_i64() {
+            json!(n)
+        } else if let Some(b) = value.to_bool() {
+            json!(b)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// A log file that's moved aside to `<path>.1`, `<path>.2`, and so on when it's rotated.
+struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl RotatingFile {
+    /// Open the file for appending, creating it if needed.
+    fn open(path: &Path, rotation: LogRotation, max_bytes: u64, keep: usize) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let opened_on = metadata
+            .modified()
+            .map(|modified| DateTime::<Local>::from(modified).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+        Ok(Self {
+            path: path.to_owned(),
+            rotation,
+            max_bytes,
+            keep,
+            file,
+            size: metadata.len(),
+            opened_on,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        let due = match self.rotation {
+            LogRotation::Daily => Local::now().date_naive() != self.opened_on,
+            LogRotation::Size => self.size > 0 && self.size + len > self.max_bytes,
+        };
+        if due {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift the old files along, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if numbered(n).exists() {
+                    fs::rename(numbered(n), numbered(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        *self = Self::open(&self.path, self.rotation, self.max_bytes, self.keep)?;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_keeps_old_files() {
+        let dir = env::temp_dir().join("ea_reminders_log_rotation_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::open(&path, LogRotation::Size, 10, 2).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("app.log"), "fourth\n");
+        assert_eq!(read("app.log.1"), "third\n");
+        assert_eq!(read("app.log.2"), "second\n");
+        assert!(!dir.join("app.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,277 +1,2075 @@
-use anyhow::{anyhow, Result};
-use chrono::{DateTime, NaiveDateTime, TimeDelta, TimeZone, Utc};
-use clap::Parser;
+use anyhow::{anyhow, Context, Result};
+use api::{get_appointments, Appointment, CustomerCache, CustomerInfo};
+use chrono::{DateTime, Datelike, TimeDelta, Utc};
+use clap::{Parser, Subcommand};
+use config::{Config, ConfigError};
+use email::Mailer;
 use itertools::Itertools;
-use lettre::{transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
 use log::{debug, error, info, warn};
-use reqwest::blocking::Client;
-use serde::Deserialize;
+use notify::Notifier;
+use reqwest::{blocking::Client, Proxy};
+use retry::RetryQueue;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
 use std::{
-    env, fs,
-    path::{Path, PathBuf},
-    process,
-    thread::sleep,
-    time::Duration,
+    collections::{HashMap, HashSet},
+    env,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
+use store::{Persisted, ReminderKey, ReminderStore, SendCounts, Store};
+use summary::DailySummary;
+use suppression::Suppressions;
+use unsubscribe::Unsubscriber;
+
+mod api;
+mod config;
+mod discord;
+mod email;
+mod http;
+mod ics;
+mod logging;
+mod metrics;
+mod notify;
+mod retry;
+mod slack;
+mod sms;
+mod store;
+mod summary;
+mod suppression;
+mod telegram;
+mod template;
+mod unsubscribe;
+mod webhook;
 
-const DEFAULT_CONFIG_FILE_NAME: &str = "reminders_config.toml";
+/// Parse `--loop-delay`, which has the same minimum as `poll_interval_seconds`.
+fn parse_loop_delay(value: &str) -> Result<u64, String> {
+    let delay = value.parse().map_err(|e| format!("{e}"))?;
+    if delay < config::MIN_POLL_INTERVAL_SECONDS {
+        return Err(format!(
+            "must be at least {} seconds",
+            config::MIN_POLL_INTERVAL_SECONDS
+        ));
+    }
+    Ok(delay)
+}
 
 /// Easy!Appointments appointment reminders.
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Load the config from a specific file.
+    /// Load the config from a specific file, or `-` for stdin.
+    ///
+    /// Can be given more than once; later files override keys from earlier ones.
     ///
     /// [default: reminders_config.toml]
     #[arg(long)]
-    config: Option<PathBuf>,
+    config: Vec<PathBuf>,
 
-    /// Enable debug logging
+    /// Enable debug logging, the same as `-v`
     #[arg(short, long)]
     debug: bool,
 
-    /// Delay between checks in seconds
-    #[arg(short, long, default_value_t = 3_600)]
-    loop_delay: u64,
+    /// Log more: `-v` for debug, `-vv` for trace, including HTTP requests
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log level, overriding `-v` and `RUST_LOG`
+    #[arg(long, value_enum)]
+    log_level: Option<logging::LogLevel>,
+
+    /// Log output format
+    #[arg(long, value_enum, env = "LOG_FORMAT", default_value_t)]
+    log_format: logging::LogFormat,
+
+    /// Delay between checks in seconds, overriding `poll_interval_seconds` from the config
+    #[arg(short, long, value_parser = parse_loop_delay)]
+    loop_delay: Option<u64>,
+
+    /// Log the reminders that would be sent, without sending them or updating the store
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Run a single check and exit, such as when scheduled by cron
+    #[arg(long)]
+    once: bool,
+
+    /// Only use the instance with this name, when the config has several
+    #[arg(long)]
+    instance: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Easy!Appointments URL and API key, and SMTP server info.
-#[derive(Debug, Deserialize)]
-struct Config {
-    api_root: String,
-    api_key: String,
-    email_from: String,
-    email_reply_to: String,
-    email_subject: String,
-    email_body: String,
-    smtp_host: String,
-    smtp_user: String,
-    smtp_pass: String,
+/// Actions other than running the reminder loop.
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the config file and exit, without touching the network
+    CheckConfig,
+    /// Check the config, the API, and the SMTP server, reporting each as pass or fail
+    Doctor,
+    /// Print the rendered reminder email, without sending it
+    Preview {
+        /// Render the email for this appointment from the API, instead of sample data
+        #[arg(long)]
+        appointment_id: Option<u32>,
+    },
+    /// Print the upcoming appointments in the reminder window, without sending anything
+    List,
+    /// Send the reminder for an appointment now, whether or not it's due
+    Send {
+        /// The appointment to send the reminder for
+        #[arg(long)]
+        appointment_id: u32,
+        /// Record the reminder in the store, so the loop doesn't send it again
+        #[arg(long)]
+        record: bool,
+    },
+    /// Send a test email through the configured SMTP server
+    TestEmail {
+        /// Address to send the test email to
+        #[arg(long)]
+        to: String,
+    },
 }
 
-impl Config {
-    /// Load the config from the specified path.
-    fn load_config(path: &Path) -> Result<Self> {
-        let text = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&text)?;
-        Ok(config)
+/// Build the HTTP client used for the API and every notification channel.
+///
+/// Responses are requested gzip or deflate compressed, which matters for large customer lists.
+fn build_client(config: &Config) -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent("github.com/Celeo/ea-appointment-reminders")
+        .timeout(Duration::from_secs(config.api_timeout_seconds))
+        .gzip(true)
+        .deflate(true);
+    if let Some(proxy) = &config.http_proxy {
+        builder = builder.proxy(Proxy::all(proxy)?);
     }
+    Ok(builder.build()?)
 }
 
-/// A single appointments's information.
+/// Print the subject and body of the reminder email to stdout.
 ///
-/// There are additional fields in the API that aren't useful here.
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Appointment {
-    id: u32,
-    start: String,
-    customer_id: u32,
+/// With an `appointment_id`, the appointment and its customer are fetched from
+/// the API; otherwise a sample appointment for tomorrow is used.
+fn preview(config: &Config, appointment_id: Option<u32>) -> Result<()> {
+    let (customer, appointment) = match appointment_id {
+        Some(id) => {
+            let client = build_client(config)?;
+            let mut appointment = api::get_appointment(&client, config, id)?;
+            api::add_names(&client, config, std::slice::from_mut(&mut appointment))?;
+            let customer = api::get_customer(&client, config, appointment.customer_id)?;
+            (customer, appointment)
+        }
+        None => {
+            let start = (Utc::now() + TimeDelta::days(1)).with_timezone(&config.timezone);
+            let customer = CustomerInfo {
+                id: 0,
+                first_name: String::from("Jane"),
+                last_name: String::from("Doe"),
+                email: String::from("jane.doe@example.com"),
+                phone: None,
+                fields: HashMap::new(),
+            };
+            let appointment = Appointment {
+                id: 0,
+                start: start.format("%Y-%m-%d %H:%M:%S").to_string(),
+                end: Some(
+                    (start + TimeDelta::hours(1))
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string(),
+                ),
+                customer_id: 0,
+                service_id: None,
+                provider_id: None,
+                status: None,
+                location: Some(String::from("123 Main St, Springfield")),
+                notes: Some(String::from("Please arrive 15 minutes early.")),
+                service_name: Some(String::from("Consultation")),
+                provider_name: Some(String::from("John Smith")),
+            };
+            (customer, appointment)
+        }
+    };
+    let email = email::render_email(&customer, &appointment, config)?;
+    println!("To: {}", customer.email);
+    println!("Subject: {}", email.subject);
+    println!();
+    println!("{}", email.body);
+    if config.email_body_html {
+        println!();
+        println!("--- Plain text ---");
+        println!();
+        println!("{}", email.text);
+    }
+    Ok(())
 }
 
-impl Appointment {
-    /// Parse the `String` timestamp into a `chrono::DateTime` struct.
-    ///
-    /// The timestamp is parsed without a timezone and then interpreted as Utc, as
-    /// the timestamp from the API does not include a timezone.
-    fn start_date(&self) -> Result<DateTime<Utc>> {
-        let naive = NaiveDateTime::parse_from_str(&self.start, "%Y-%m-%d %H:%M:%S")?;
-        match Utc.from_local_datetime(&naive) {
-            chrono::LocalResult::Single(t) => Ok(t),
-            _ => Err(anyhow!("Could not parse datetime")),
+/// Check that each instance's API and SMTP server can be reached, printing a line for each.
+///
+/// Fails if any of the checks do.
+fn doctor(configs: &[Config]) -> Result<()> {
+    let mut failed = 0;
+    for config in configs {
+        let prefix = config
+            .instance
+            .as_ref()
+            .map(|name| format!("{name} "))
+            .unwrap_or_default();
+        let checks = [
+            (
+                "api",
+                "check api_root, api_key, and that this machine can reach the server",
+                build_client(config).and_then(|client| api::ping(&client, config)),
+            ),
+            (
+                "smtp",
+                "check smtp_host, smtp_port, smtp_tls, smtp_user, and smtp_pass",
+                Mailer::new(config).and_then(|mailer| mailer.test_connection()),
+            ),
+        ];
+        for (name, hint, result) in checks {
+            match result {
+                Ok(()) => println!("PASS {prefix}{name}"),
+                Err(e) => {
+                    failed += 1;
+                    println!("FAIL {prefix}{name}: {e:#}");
+                    println!("     {hint}");
+                }
+            }
         }
     }
+    if failed > 0 {
+        return Err(anyhow!("{failed} of the checks failed"));
+    }
+    Ok(())
 }
 
-/// A single customer's information.
-///
-/// There are additional fields in the API that aren't useful here.
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct CustomerInfo {
-    id: u32,
-    first_name: String,
-    last_name: String,
-    email: String,
+/// Print a table of the appointments within the largest reminder offset, with their
+/// customers and when a reminder was last sent for them.
+fn list(
+    config: &Config,
+    reminders: &dyn ReminderStore,
+    retries: &RetryQueue,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let client = build_client(config)?;
+    let window = config
+        .reminder_offsets()
+        .into_iter()
+        .max()
+        .unwrap_or_default();
+    let customers: HashMap<u32, CustomerInfo> = api::get_customers(&client, config)?
+        .into_iter()
+        .map(|customer| (customer.id, customer))
+        .collect();
+    let mut upcoming = Vec::new();
+    for appointment in get_appointments(&client, config, now)? {
+        let start = appointment.start_date(config.timezone)?;
+        if start > now && (start - now).to_std()? <= window {
+            upcoming.push((start, appointment));
+        }
+    }
+    upcoming.sort_by_key(|(start, _)| *start);
+
+    println!(
+        "{:<8} {:<20} {:<30} {:<30} Reminded",
+        "ID", "Start", "Customer", "Email"
+    );
+    for (_, appointment) in &upcoming {
+        let (name, email) = match customers.get(&appointment.customer_id) {
+            Some(c) => (
+                format!("{} {}", c.first_name, c.last_name),
+                c.email.as_str(),
+            ),
+            None => (format!("(unknown {})", appointment.customer_id), ""),
+        };
+        println!(
+            "{:<8} {:<20} {:<30} {:<30} {}",
+            appointment.id,
+            appointment.start,
+            name,
+            email,
+            reminded(config, reminders, retries, appointment)
+        );
+    }
+    println!();
+    println!("{} upcoming appointments", upcoming.len());
+    Ok(())
 }
 
-/// Get appointments from the API.
-fn get_appointments(client: &Client, config: &Config) -> Result<Vec<Appointment>> {
-    let resp = client
-        .get(format!("{}appointments", config.api_root))
-        .header(
-            reqwest::header::AUTHORIZATION,
-            &format!("Bearer {}", config.api_key),
-        )
-        .send()?;
-    if !resp.status().is_success() {
-        return Err(anyhow!(
-            "Got status {} from appointments API",
-            resp.status().as_u16()
-        ));
+/// Whether a reminder has been sent for the appointment, and when the last one was, for `list`.
+///
+/// If the appointment was rescheduled, this also says when a reminder was sent for the
+/// earlier start. Reminders that were given up on are shown with how many attempts were made.
+fn reminded(
+    config: &Config,
+    reminders: &dyn ReminderStore,
+    retries: &RetryQueue,
+    appointment: &Appointment,
+) -> String {
+    let offsets = config
+        .reminder_offsets()
+        .iter()
+        .map(Duration::as_secs)
+        .collect_vec();
+    let (current, earlier): (Vec<_>, Vec<_>) = reminders
+        .sent_for(appointment.id)
+        .into_iter()
+        .filter(|((_, offset, _), _)| offsets.contains(offset))
+        .partition(|((_, _, start), _)| {
+            start.is_none() || start.as_deref() == Some(appointment.start.as_str())
+        });
+    let format = |sent: DateTime<Utc>| {
+        sent.with_timezone(&config.timezone)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    };
+    if !current.is_empty() {
+        return match current.into_iter().filter_map(|(_, sent)| sent).max() {
+            Some(sent) => format!("yes, at {}", format(sent)),
+            None => String::from("yes"),
+        };
+    }
+    let given_up = offsets
+        .iter()
+        .filter_map(|offset| {
+            retries.gave_up(&(appointment.id, *offset, Some(appointment.start.clone())))
+        })
+        .max();
+    if let Some(attempts) = given_up {
+        return format!("no, gave up after {attempts} attempts");
+    }
+    match earlier.into_iter().max_by_key(|(_, sent)| *sent) {
+        Some(((_, _, start), sent)) => {
+            let start = start.as_deref().unwrap_or_default();
+            match sent {
+                Some(sent) => format!("no, but sent at {} for {start}", format(sent)),
+                None => format!("no, but sent for {start}"),
+            }
+        }
+        None => String::from("no"),
     }
-    let data = resp.json()?;
-    Ok(data)
 }
 
-/// Get customers from the API.
-fn get_customers(client: &Client, config: &Config) -> Result<Vec<CustomerInfo>> {
-    let resp = client
-        .get(format!("{}customers", config.api_root))
-        .header(
-            reqwest::header::AUTHORIZATION,
-            &format!("Bearer {}", config.api_key),
-        )
-        .send()?;
-    if !resp.status().is_success() {
+/// Send the reminder for one appointment right away, through every notifier.
+///
+/// Returns the reminders to record as sent: one for each offset the appointment is
+/// already within, like `check` would record.
+fn send_now(
+    client: &Client,
+    config: &Config,
+    notifiers: &[Box<dyn Notifier + '_>],
+    appointment_id: u32,
+    now: DateTime<Utc>,
+) -> Result<Vec<ReminderKey>> {
+    let mut appointment = api::get_appointment(client, config, appointment_id)?;
+    api::add_names(client, config, std::slice::from_mut(&mut appointment))?;
+    let customer = api::get_customer(client, config, appointment.customer_id)?;
+    let until = (appointment.start_date(config.timezone)? - now).to_std();
+    let due = config
+        .reminder_offsets()
+        .iter()
+        .filter(|offset| until.is_ok_and(|until| until <= **offset))
+        .map(Duration::as_secs)
+        .collect_vec();
+    let pending = Pending {
+        appointment,
+        customer: &customer,
+        due,
+    };
+    let skip = vec![false; notifiers.len()];
+    if !send_group(notifiers, std::slice::from_ref(&pending), &skip).0 {
         return Err(anyhow!(
-            "Got status {} from appointments API",
-            resp.status().as_u16()
+            "Could not send the reminder for appointment #{appointment_id}"
         ));
     }
-    let data = resp.json()?;
-    Ok(data)
+    Ok(pending.keys().collect())
 }
 
-/// Send an email to the customer to remind them of the upcoming appointment.
-fn send_notification(
-    customer_info: &CustomerInfo,
-    appointment_datetime: &str,
-    config: &Config,
-) -> Result<()> {
-    let body = config
-        .email_body
-        .replace("%APPOINTMENT_DATETIME%", appointment_datetime)
-        .replace("%FIRST_NAME%", &customer_info.first_name)
-        .replace("%LAST_NAME%", &customer_info.last_name);
-    let email = Message::builder()
-        .from(config.email_from.parse()?)
-        .reply_to(config.email_reply_to.parse()?)
-        .to(customer_info.email.parse()?)
-        .subject(&config.email_subject)
-        .body(body)?;
-
-    let sender = SmtpTransport::relay(&config.smtp_host)?
-        .credentials(Credentials::from((&config.smtp_user, &config.smtp_pass)))
-        .build();
-    let result = sender.send(&email)?;
-    if result.is_positive() {
-        debug!("Email notification sent");
-    } else {
-        warn!(
-            "Error response code from sending email to {}, code {}",
-            customer_info.email,
-            result.code()
+/// Open the reminders store and load the reminders sent so far.
+fn open_store(config: &Config) -> Result<Persisted> {
+    debug!("Reading from reminders store");
+    let legacy_offset = config.reminder_window_days as u64 * 24 * 60 * 60;
+    Store::open(
+        config.reminders_store,
+        config.reminders_file.as_deref(),
+        legacy_offset,
+    )
+    .and_then(Persisted::load)
+    .context("Could not read from reminders store")
+}
+
+/// What happened to the reminders that were due in a check.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// IDs of the appointments that reminders were sent for.
+    pub sent: Vec<u32>,
+    /// IDs of the appointments whose reminders couldn't be sent.
+    pub failed: Vec<u32>,
+    /// The reminders that couldn't be sent, to retry.
+    pub retry: Vec<ReminderKey>,
+    /// Whether the check was skipped for quiet hours or a day outside `send_days`,
+    /// so nothing was tried.
+    pub deferred: bool,
+}
+
+/// Count another attempt for each reminder that failed in the check, if retries are on.
+///
+/// A deferred check didn't try anything, so the queue is left as it was.
+fn record_retries(config: &Config, retries: &mut RetryQueue, report: &CheckReport) {
+    if config.retry_interval_seconds.is_none() || report.deferred {
+        return;
+    }
+    for (key, attempts) in retries.record(&report.retry, config.retry_max_attempts) {
+        error!(
+            appointment_id = key.0;
+            "Giving up on the reminder for appointment #{} after {attempts} attempts",
+            key.0
         );
     }
+}
 
-    Ok(())
+/// Randomly shift the delay between checks by up to `jitter` seconds either way.
+fn jittered(interval: u64, jitter: u64, rng: &mut fastrand::Rng) -> Duration {
+    let low = interval.saturating_sub(jitter);
+    Duration::from_secs(rng.u64(low..=interval + jitter))
+}
+
+/// An appointment that has a reminder due.
+struct Pending<'a> {
+    appointment: Appointment,
+    customer: &'a CustomerInfo,
+    /// The reminder offsets that this reminder covers.
+    due: Vec<u64>,
+}
+
+impl Pending<'_> {
+    /// The key of the reminder for each offset this one covers.
+    fn keys(&self) -> impl Iterator<Item = ReminderKey> + '_ {
+        self.due.iter().map(|offset| {
+            (
+                self.appointment.id,
+                *offset,
+                Some(self.appointment.start.clone()),
+            )
+        })
+    }
 }
 
 /// Access to the Easy!Appointments instance, check for upcoming appointments, and potentially send reminders.
-fn check(config: &Config, reminders_set: &mut Vec<u32>) -> Result<()> {
-    let client = reqwest::blocking::Client::builder()
-        .user_agent("github.com/Celeo/ea-appointment-reminders")
-        .build()
-        .unwrap();
-    let appointments = get_appointments(&client, config)?;
-    let customers = get_customers(&client, config)?;
-    let now = Utc::now();
+///
+/// Each of the `notifiers` sends a single reminder per appointment per check, covering
+/// every reminder offset that has been crossed since the last check. In `digest` mode,
+/// a customer with more than one appointment due gets a single reminder covering all
+/// of them instead.
+///
+/// Customers in the `suppression_file` are skipped; the file is read again on every
+/// check, so changes take effect without a restart.
+///
+/// Reminders are sent soonest appointment first, whatever order the API returns them in.
+///
+/// Sent reminders are keyed on the appointment's start as well, so an appointment that
+/// has been rescheduled gets its reminders again.
+///
+/// Reminders for appointments that started more than `reminder_retention_days` ago
+/// are pruned from `reminders`, going by the start they were sent for, so they're pruned
+/// even when the API no longer returns those appointments.
+///
+/// Customers who have already been sent `max_reminders_per_customer_per_day`
+/// reminders today, as counted in `send_counts`, get the rest on a later check.
+/// Past `max_appointments_per_cycle` appointments, the rest also wait for a later check.
+///
+/// All reminder timing is relative to `now`, rather than the system clock.
+///
+/// Reminders that `retries` has given up on aren't sent again.
+///
+/// If `dry_run` is set, nothing is sent and `reminders` is left unchanged.
+#[allow(clippy::too_many_arguments)]
+fn check(
+    client: &Client,
+    config: &Config,
+    notifiers: &[Box<dyn Notifier + '_>],
+    reminders: &mut dyn ReminderStore,
+    retries: &mut RetryQueue,
+    send_counts: &mut SendCounts,
+    customer_cache: &mut CustomerCache,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    if let Some(remaining) = config.quiet_hours_remaining(now) {
+        info!(
+            "In quiet hours for another {}, deferring reminders",
+            humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+        );
+        report.deferred = true;
+        return Ok(report);
+    }
+    if !config.is_send_day(now) {
+        info!(
+            "Not sending reminders on {}, deferring them",
+            now.with_timezone(&config.timezone).weekday()
+        );
+        report.deferred = true;
+        return Ok(report);
+    }
+    let suppressions = match &config.suppression_file {
+        Some(path) => Suppressions::load(path)?,
+        None => Suppressions::default(),
+    };
+    let mut appointments = get_appointments(client, config, now)?;
+    // an unparseable start sorts first, and fails the check below
+    appointments.sort_by_cached_key(|appointment| appointment.start_date(config.timezone).ok());
+    api::add_names(client, config, &mut appointments)?;
+    let customers = customer_cache.get(
+        client,
+        config,
+        appointments
+            .iter()
+            .map(|appointment| appointment.customer_id),
+        now,
+    )?;
+    let offsets = config.reminder_offsets();
+
+    let retention_cutoff = now - TimeDelta::days(config.reminder_retention_days);
+    let expired: HashSet<u32> = appointments
+        .iter()
+        .filter(|appointment| {
+            appointment
+                .start_date(config.timezone)
+                .is_ok_and(|date| date < retention_cutoff)
+        })
+        .map(|appointment| appointment.id)
+        .collect();
 
+    let mut pending = Vec::new();
+    let mut seen = HashSet::new();
     for appointment in appointments {
-        if reminders_set.contains(&appointment.id) {
-            debug!("Already notified for #{}", appointment.id);
+        if !config.is_included(&appointment) {
+            debug!(
+                "Skipping appointment #{} for its service, provider, or status",
+                appointment.id
+            );
             continue;
         }
-        let date = appointment.start_date()?;
-        if date <= now {
+        let date = appointment.start_date(config.timezone)?;
+        if date <= now - TimeDelta::minutes(config.past_grace_minutes.into()) {
             // in the past
             continue;
         }
-        if date - now > TimeDelta::days(3) {
-            // more than 3 days out
+        // an appointment that just started, within the grace, has crossed every offset
+        let until = (date - now).to_std().unwrap_or_default();
+        let crossed = offsets
+            .iter()
+            .filter(|offset| until <= **offset)
+            .map(Duration::as_secs)
+            .collect_vec();
+        if crossed.is_empty() {
+            // too far out
             continue;
         }
-        debug!("Upcoming appointment #{}", appointment.id);
-        let customer = match customers.iter().find(|c| c.id == appointment.customer_id) {
+        let local_date = date.with_timezone(&config.timezone).date_naive();
+        if config.holidays.contains(&local_date) {
+            info!(
+                appointment_id = appointment.id;
+                "Skipping appointment #{} on the holiday {local_date}",
+                appointment.id
+            );
+            continue;
+        }
+        let due = crossed
+            .into_iter()
+            .filter(|offset| {
+                let key = (appointment.id, *offset, Some(appointment.start.clone()));
+                !reminders.contains(&key)
+                    && !reminders.contains(&(appointment.id, *offset, None))
+                    && retries.gave_up(&key).is_none()
+            })
+            .collect_vec();
+        if due.is_empty() {
+            debug!("Already notified for #{}", appointment.id);
+            continue;
+        }
+        debug!(appointment_id = appointment.id; "Upcoming appointment #{}", appointment.id);
+        let customer = match customers.get(&appointment.customer_id) {
             Some(c) => c,
             None => {
                 error!(
+                    appointment_id = appointment.id, customer_id = appointment.customer_id;
                     "Could not find email for customer {}",
                     appointment.customer_id
                 );
                 continue;
             }
         };
-        send_notification(customer, &appointment.start, config)?;
+        if !email::is_valid_address(&customer.email) {
+            warn!(
+                appointment_id = appointment.id, customer_id = customer.id;
+                "Skipping appointment #{} for customer {}, whose email {:?} is not valid",
+                appointment.id,
+                customer.id,
+                customer.email
+            );
+            continue;
+        }
+        if config.is_opted_out(customer) {
+            debug!(
+                appointment_id = appointment.id, customer_id = customer.id;
+                "Skipping appointment #{} for customer {}, who opted out",
+                appointment.id,
+                customer.id
+            );
+            continue;
+        }
+        if suppressions.contains(customer) {
+            debug!(
+                appointment_id = appointment.id, customer_id = customer.id;
+                "Skipping appointment #{} for suppressed customer {}",
+                appointment.id,
+                customer.id
+            );
+            continue;
+        }
+        if !seen.insert((config.customer_key(customer), appointment.start.clone())) {
+            info!(
+                appointment_id = appointment.id, customer_id = customer.id;
+                "Skipping appointment #{} for customer {}, the same person as another customer \
+                with an appointment at {}",
+                appointment.id,
+                customer.id,
+                appointment.start
+            );
+            continue;
+        }
+        pending.push(Pending {
+            appointment,
+            customer,
+            due,
+        });
+    }
+    let mut groups: Vec<Vec<Pending>> = if config.digest {
+        pending
+            .into_iter()
+            .into_group_map_by(|p| config.customer_key(p.customer))
+            .into_values()
+            .sorted_by_cached_key(|group| group[0].appointment.start_date(config.timezone).ok())
+            .collect()
+    } else {
+        pending.into_iter().map(|p| vec![p]).collect()
+    };
+    // before the cycle cap, so deferred customers don't take up the cycle's slots; a
+    // digest is one email, so it counts as one reminder
+    let today = now.with_timezone(&config.timezone).date_naive();
+    if let Some(cap) = config.max_reminders_per_customer_per_day {
+        let mut counts = HashMap::new();
+        groups.retain(|group| {
+            let customer = group[0].customer;
+            let key = config.customer_key(customer);
+            let count = counts
+                .entry(key)
+                .or_insert_with_key(|key| send_counts.get(key, today));
+            if *count >= cap {
+                info!(
+                    customer_id = customer.id;
+                    "Customer {} has had {cap} reminders today, deferring appointment(s) {}",
+                    customer.id,
+                    group.iter().map(|p| format!("#{}", p.appointment.id)).join(", ")
+                );
+                return false;
+            }
+            *count += 1;
+            true
+        });
+    }
+    if let Some(cap) = config.max_appointments_per_cycle {
+        // whole groups are kept or deferred, so a customer's digest isn't split across checks
+        let total: usize = groups.iter().map(Vec::len).sum();
+        let (mut kept, mut count) = (0, 0);
+        for group in &groups {
+            if kept > 0 && count + group.len() > cap as usize {
+                break;
+            }
+            kept += 1;
+            count += group.len();
+        }
+        if kept < groups.len() {
+            warn!(
+                "{total} appointments are due reminders, more than max_appointments_per_cycle; \
+                deferring the {} latest to the next check",
+                total - count
+            );
+            groups.truncate(kept);
+        }
+    }
+    // the channels each group was already sent through, on an earlier check
+    let skip = groups
+        .iter()
+        .map(|group| {
+            notifiers
+                .iter()
+                .map(|notifier| {
+                    group
+                        .iter()
+                        .flat_map(Pending::keys)
+                        .all(|key| reminders.sent_through(&key, notifier.name()))
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+    let items = groups.iter().zip(&skip).collect_vec();
+    let results = map_bounded(&items, config.max_concurrent_sends, |(group, skip)| {
+        send_group(notifiers, group, skip)
+    });
+    for (group, (sent, channels)) in groups.into_iter().zip(results) {
+        if !sent {
+            for p in &group {
+                report.failed.push(p.appointment.id);
+                report.retry.extend(p.keys());
+                if !dry_run {
+                    for key in p.keys() {
+                        for channel in &channels {
+                            reminders.mark_channel(key.clone(), channel);
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+        if dry_run {
+            continue;
+        }
+        let customer = group[0].customer;
+        send_counts.add(&config.customer_key(customer), today);
+        for p in group {
+            metrics::REMINDERS_SENT.inc();
+            report.sent.push(p.appointment.id);
+            info!(
+                appointment_id = p.appointment.id, customer_id = customer.id;
+                "Adding appointment #{} to the list of sent reminders",
+                p.appointment.id
+            );
+            let before = reminders.len();
+            reminders.retain(&mut |(id, _, start)| {
+                *id != p.appointment.id
+                    || start
+                        .as_ref()
+                        .is_none_or(|start| *start == p.appointment.start)
+            });
+            if reminders.len() < before {
+                info!(
+                    appointment_id = p.appointment.id;
+                    "Appointment #{} was rescheduled to {}",
+                    p.appointment.id,
+                    p.appointment.start
+                );
+            }
+            if config.write_back_reminder {
+                if let Err(e) = api::add_reminder_note(client, config, &p.appointment, now) {
+                    error!(
+                        appointment_id = p.appointment.id;
+                        "Could not add the reminder note to appointment #{}: {e}",
+                        p.appointment.id
+                    );
+                }
+            }
+            for key in p.keys() {
+                reminders.mark(key, now);
+            }
+        }
+    }
+
+    if !dry_run {
+        let before = reminders.len();
+        let mut keep = |(id, _, start): &ReminderKey| match start {
+            Some(start) => api::parse_timestamp(start, config.timezone)
+                .map_or(true, |date| date >= retention_cutoff),
+            None => !expired.contains(id),
+        };
+        reminders.retain(&mut keep);
+        retries.retain(&mut keep);
+        if reminders.len() < before {
+            debug!(
+                "Pruned {} reminders for past appointments",
+                before - reminders.len()
+            );
+        }
+    }
+
+    Ok(report)
+}
+
+/// Send a customer's reminders through each of the notifiers in turn, except those that
+/// `skip` is set for, which they were already sent through.
+///
+/// Returns whether they were sent, which is false if a required notifier failed, and the
+/// channels they were sent through.
+fn send_group(
+    notifiers: &[Box<dyn Notifier + '_>],
+    group: &[Pending],
+    skip: &[bool],
+) -> (bool, Vec<&'static str>) {
+    let customer = group[0].customer;
+    let appointments = group.iter().map(|p| &p.appointment).collect_vec();
+    let mut channels = Vec::new();
+    for (notifier, skip) in notifiers.iter().zip(skip) {
+        if *skip {
+            debug!(
+                customer_id = customer.id;
+                "Already sent the {} reminder to customer {}",
+                notifier.name(),
+                customer.id
+            );
+            continue;
+        }
+        let result = match appointments.as_slice() {
+            [single] => notifier.notify(customer, single),
+            _ => notifier.notify_many(customer, &appointments),
+        };
+        if let Err(e) = result {
+            error!(
+                customer_id = customer.id;
+                "Could not send {} reminder for appointment(s) {} to customer {}: {e}",
+                notifier.name(),
+                appointments.iter().map(|a| format!("#{}", a.id)).join(", "),
+                customer.id
+            );
+            if notifier.required() {
+                return (false, channels);
+            }
+        } else {
+            channels.push(notifier.name());
+        }
+    }
+    (true, channels)
+}
+
+/// Call `f` on each item from up to `limit` threads at once, returning the results in order.
+fn map_bounded<T: Sync, R: Send>(items: &[T], limit: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let workers = limit.clamp(1, items.len().max(1));
+    if workers == 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break done;
+                        };
+                        done.push((index, f(item)));
+                    }
+                })
+            })
+            .collect_vec();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Send thread panicked"))
+            .collect_vec()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Something that the main loop handles between checks.
+pub enum Event {
+    /// SIGINT or SIGTERM was received.
+    Shutdown(i32),
+    /// SIGHUP was received, or `/reload` was requested.
+    Reload,
+    /// SIGUSR1 was received, to check for reminders right away.
+    CheckNow,
+}
+
+/// Listen for SIGINT, SIGTERM, SIGHUP, and SIGUSR1, forwarding them to the returned channel.
+///
+/// The main loop only waits on this channel between checks, so a signal never
+/// interrupts a reminder that's in the middle of being sent. The sender can be
+/// cloned to send events from elsewhere, like the HTTP server.
+fn listen_for_events() -> Result<(Sender<Event>, Receiver<Event>)> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR1])?;
+    let (sender, receiver) = mpsc::channel();
+    let signal_sender = sender.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = match signal {
+                SIGHUP => Event::Reload,
+                SIGUSR1 => Event::CheckNow,
+                signal => Event::Shutdown(signal),
+            };
+            if signal_sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((sender, receiver))
+}
+
+/// An instance's config, and everything built from it that's kept between checks.
+struct Instance {
+    config: Config,
+    client: Client,
+    mailer: Mailer,
+    reminders: Persisted,
+    send_counts: SendCounts,
+    customer_cache: CustomerCache,
+    retries: RetryQueue,
+    summary: DailySummary,
+}
+
+impl Instance {
+    /// Open the instance's reminders store, and build its HTTP client and mailer.
+    fn open(config: Config) -> Result<Self> {
+        let mut reminders = open_store(&config)?;
+        let send_counts = reminders
+            .store_mut()
+            .load_send_counts()
+            .context("Could not read from reminders store")?;
+        let retries = reminders
+            .store_mut()
+            .load_retries()
+            .context("Could not read from reminders store")?;
         info!(
-            "Adding appointment #{} to the list of sent reminders",
-            appointment.id
+            "Loaded {} existing reminder IDs from store{}",
+            reminders.len(),
+            instance_suffix(&config)
         );
-        reminders_set.push(appointment.id);
+        Ok(Self {
+            client: build_client(&config).context("Could not build HTTP client")?,
+            mailer: Mailer::new(&config).context("Could not set up SMTP")?,
+            summary: DailySummary::new(&config, Utc::now()),
+            config,
+            reminders,
+            send_counts,
+            customer_cache: CustomerCache::default(),
+            retries,
+        })
     }
 
-    Ok(())
+    /// Write the sent reminders, daily send counts, and retries to the store.
+    fn flush(&mut self) -> Result<()> {
+        self.reminders.flush()?;
+        let store = self.reminders.store_mut();
+        store.save_send_counts(&self.send_counts)?;
+        store.save_retries(&self.retries)
+    }
+}
+
+/// " for instance <name>" when the config is one of several instances, for log messages.
+fn instance_suffix(config: &Config) -> String {
+    config
+        .instance
+        .as_ref()
+        .map(|name| format!(" for instance {name}"))
+        .unwrap_or_default()
+}
+
+/// Keep only the instance named by `--instance`, if it was given.
+fn select_instances(configs: Vec<Config>, name: Option<&str>) -> Result<Vec<Config>> {
+    let Some(name) = name else {
+        return Ok(configs);
+    };
+    let config = configs
+        .into_iter()
+        .find(|config| config.instance.as_deref() == Some(name))
+        .ok_or_else(|| anyhow!("There is no instance named {name} in the config"))?;
+    Ok(vec![config])
+}
+
+/// Load the config again, along with the HTTP clients and mailers built from it.
+///
+/// If the new config can't be loaded or used, or its instances aren't the same ones, the
+/// current one is kept.
+fn reload_config(locations: &[PathBuf], selected: Option<&str>, instances: &mut [Instance]) {
+    let reloaded = Config::load_instances(locations)
+        .and_then(|configs| select_instances(configs, selected))
+        .and_then(|configs| {
+            let names = configs.iter().map(|config| &config.instance).collect_vec();
+            if names != instances.iter().map(|i| &i.config.instance).collect_vec() {
+                return Err(anyhow!(
+                    "The instances changed, restart to use the new ones"
+                ));
+            }
+            configs
+                .into_iter()
+                .map(|new| {
+                    let new_client = build_client(&new).context("Could not build HTTP client")?;
+                    let new_mailer = Mailer::new(&new).context("Could not set up SMTP")?;
+                    Ok((new, new_client, new_mailer))
+                })
+                .collect::<Result<Vec<_>>>()
+        });
+    match reloaded {
+        Ok(reloaded) => {
+            for (instance, (new, new_client, new_mailer)) in instances.iter_mut().zip(reloaded) {
+                instance.config = new;
+                instance.client = new_client;
+                instance.mailer = new_mailer;
+            }
+            info!("Reloaded the config");
+        }
+        Err(e) => error!("Could not reload the config, keeping the current one: {e:#}"),
+    }
 }
 
 /// Entrypoint.
-fn main() {
+///
+/// Exits with 2 if the config couldn't be loaded, or 1 for any other error.
+fn main() -> ExitCode {
     let cli = Cli::parse();
-    if cli.debug {
-        env::set_var("RUST_LOG", "info,ea_appointment_reminders=debug");
-    } else if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info");
+    let verbosity = cli.verbose.max(u8::from(cli.debug));
+    match cli.log_level {
+        Some(level) => env::set_var("RUST_LOG", level.filters()),
+        None if verbosity > 0 || env::var("RUST_LOG").is_err() => env::set_var(
+            "RUST_LOG",
+            logging::LogLevel::from_verbosity(verbosity).filters(),
+        ),
+        None => {}
     }
-    pretty_env_logger::init();
+    logging::init(cli.log_format);
     debug!("Logging configured");
 
-    let config_location = match cli.config {
-        Some(path) => path,
-        None => Path::new(DEFAULT_CONFIG_FILE_NAME).to_owned(),
-    };
-    debug!("Loading from config file at: {}", config_location.display());
-    let config = match Config::load_config(&config_location) {
-        Ok(c) => c,
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            error!("Could not load config: {e}");
-            process::exit(1);
+            error!("{e:#}");
+            if e.is::<ConfigError>() {
+                ExitCode::from(2)
+            } else {
+                ExitCode::FAILURE
+            }
         }
+    }
+}
+
+/// Run the command, or the reminder loop if there isn't one.
+fn run(cli: &Cli) -> Result<()> {
+    // with none given, the default file is read if it exists
+    let config_locations = cli.config.clone();
+    if !config_locations.is_empty() {
+        debug!(
+            "Loading from config files at: {}",
+            config_locations
+                .iter()
+                .map(|path| path.display())
+                .join(", ")
+        );
+    }
+
+    let configs = match Config::load_instances(&config_locations)
+        .and_then(|configs| select_instances(configs, cli.instance.as_deref()))
+    {
+        Ok(configs) => configs,
+        Err(e) => {
+            if matches!(cli.command, Some(Command::Doctor)) {
+                println!("FAIL config: {e:#}");
+            }
+            return Err(e.context(ConfigError));
+        }
+    };
+    // settings for the process as a whole, rather than for each instance, come from the first
+    let config = &configs[0];
+    if let Some(path) = &config.log_file {
+        logging::log_to_file(
+            path,
+            config.log_rotation,
+            config.log_max_size_mb * 1024 * 1024,
+            config.log_keep_files,
+        )
+        .with_context(|| format!("Could not open log file {}", path.display()))?;
+        debug!("Writing logs to {}", path.display());
+    }
+    if configs.len() > 1
+        && !matches!(
+            cli.command,
+            None | Some(Command::CheckConfig | Command::Doctor)
+        )
+    {
+        info!(
+            "Using the first instance{}, pass --instance to pick another",
+            instance_suffix(config)
+        );
+    }
+
+    match &cli.command {
+        Some(Command::CheckConfig) => {
+            println!("Config OK");
+            return Ok(());
+        }
+        Some(Command::Preview { appointment_id }) => return preview(config, *appointment_id),
+        Some(Command::Doctor) => {
+            println!("PASS config");
+            return doctor(&configs);
+        }
+        Some(Command::List) => {
+            let mut reminders = open_store(config)?;
+            let retries = reminders
+                .store_mut()
+                .load_retries()
+                .context("Could not read from reminders store")?;
+            return list(config, &reminders, &retries, Utc::now());
+        }
+        Some(Command::Send {
+            appointment_id,
+            record,
+        }) => {
+            let client = build_client(config).context("Could not build HTTP client")?;
+            let mailer = Mailer::new(config).context("Could not set up SMTP")?;
+            let notifiers = notify::from_config(config, &client, &mailer, cli.dry_run);
+            let now = Utc::now();
+            let sent = send_now(&client, config, &notifiers, *appointment_id, now)?;
+            if *record && !cli.dry_run {
+                let mut reminders = open_store(config)?;
+                for key in sent {
+                    reminders.mark(key, now);
+                }
+                reminders
+                    .flush()
+                    .context("Error writing to reminders store")?;
+            }
+            println!("Reminder sent for appointment #{appointment_id}");
+            return Ok(());
+        }
+        Some(Command::TestEmail { to }) => {
+            let mailer = Mailer::new(config).context("Could not set up SMTP")?;
+            email::send_test_email(&mailer, config, to).context("Could not send test email")?;
+            println!("Test email sent to {to}");
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let (sender, events) = listen_for_events().context("Could not register signal handlers")?;
+    let mut instances = configs
+        .into_iter()
+        .map(Instance::open)
+        .collect::<Result<Vec<_>>>()?;
+    let config = &instances[0].config;
+
+    let poll_interval = cli.loop_delay.unwrap_or(config.poll_interval_seconds);
+    info!("Checking for reminders every {poll_interval} seconds");
+
+    let status = Arc::new(http::Status::new(poll_interval));
+    for port in [config.health_port, config.metrics_port]
+        .into_iter()
+        .flatten()
+        .unique()
+    {
+        http::serve(port, Arc::clone(&status), sender.clone())
+            .with_context(|| format!("Could not start HTTP server on port {port}"))?;
+    }
+    // the instances that serve unsubscribe links all have the same settings for it
+    if let Some((secret, path, port, address)) = instances.iter().find_map(|instance| {
+        let config = &instance.config;
+        Some((
+            config.unsubscribe_secret.as_ref()?,
+            config.suppression_file.as_ref()?,
+            config.unsubscribe_port?,
+            &config.unsubscribe_bind_address,
+        ))
+    }) {
+        let unsubscriber = Unsubscriber::new(secret.clone(), path.clone());
+        http::serve_unsubscribe(address, port, unsubscriber)
+            .with_context(|| format!("Could not start the unsubscribe server on port {port}"))?;
+    }
+
+    let mut rng = match config.poll_jitter_seed {
+        Some(seed) => fastrand::Rng::with_seed(seed),
+        None => fastrand::Rng::new(),
+    };
+    'main: loop {
+        let mut succeeded = true;
+        for instance in &mut instances {
+            let Instance {
+                config,
+                client,
+                mailer,
+                reminders,
+                send_counts,
+                customer_cache,
+                retries,
+                summary,
+            } = instance;
+            info!("Checking for reminders{}", instance_suffix(config));
+            // rebuilt for every check, so they pick up a reloaded config
+            let notifiers = notify::from_config(config, client, mailer, cli.dry_run);
+            match check(
+                client,
+                config,
+                &notifiers,
+                reminders,
+                retries,
+                send_counts,
+                customer_cache,
+                Utc::now(),
+                cli.dry_run,
+            ) {
+                Ok(report) => {
+                    summary.record(&report);
+                    record_retries(config, retries, &report);
+                }
+                Err(e) => {
+                    error!(
+                        "Error processing potential reminders{}: {e}",
+                        instance_suffix(config)
+                    );
+                    summary.record_failed_check();
+                    succeeded = false;
+                }
+            };
+            drop(notifiers);
+            if summary.is_due(config, Utc::now()) {
+                if let Err(e) = summary.send(config, mailer, Utc::now(), cli.dry_run) {
+                    error!(
+                        "Could not send the daily summary{}: {e}",
+                        instance_suffix(config)
+                    );
+                }
+            }
+            if !cli.dry_run {
+                if let Err(e) = instance.flush() {
+                    error!("Error writing to reminders store: {e}");
+                    succeeded = false;
+                }
+            }
+        }
+        let config = &instances[0].config;
+        status.record_check();
+        metrics::LAST_CHECK.set(Utc::now().timestamp());
+        if cli.once {
+            return if succeeded {
+                Ok(())
+            } else {
+                Err(anyhow!("The check did not succeed"))
+            };
+        }
+        let poll_interval = cli.loop_delay.unwrap_or(config.poll_interval_seconds);
+        let mut interval = jittered(poll_interval, config.poll_jitter_seconds, &mut rng);
+        for instance in &instances {
+            if let Some(retry) = instance
+                .config
+                .retry_interval_seconds
+                .and_then(|base| instance.retries.delay(Duration::from_secs(base)))
+            {
+                info!(
+                    "Retrying {} failed reminders{} in {} seconds",
+                    instance.retries.len(),
+                    instance_suffix(&instance.config),
+                    retry.as_secs()
+                );
+                interval = interval.min(retry);
+            }
+        }
+        // wake up as soon as quiet hours end, rather than waiting for the next interval
+        let delay = match instances
+            .iter()
+            .filter_map(|instance| instance.config.quiet_hours_remaining(Utc::now()))
+            .min()
+        {
+            Some(remaining) => (remaining + Duration::from_secs(1)).min(interval),
+            None => interval,
+        };
+        debug!("Sleeping for {} seconds", delay.as_secs());
+        let wake_at = Instant::now() + delay;
+        loop {
+            match events.recv_timeout(wake_at.saturating_duration_since(Instant::now())) {
+                Ok(Event::Shutdown(signal)) => {
+                    info!("Received signal {signal}, shutting down");
+                    break 'main;
+                }
+                Ok(Event::Reload) => {
+                    info!("Reloading the config");
+                    reload_config(&config_locations, cli.instance.as_deref(), &mut instances);
+                }
+                Ok(Event::CheckNow) => {
+                    info!("Received SIGUSR1, checking now");
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!("Signal handler stopped, shutting down");
+                    break 'main;
+                }
+            }
+        }
+    }
+
+    if !cli.dry_run {
+        for instance in &mut instances {
+            instance
+                .flush()
+                .context("Error writing to reminders store")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+    use tokio::runtime::Runtime;
+    use wiremock::{
+        matchers::{body_json, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
     };
 
-    let reminders_file = Path::new("reminders.txt");
-    let mut reminders_set: Vec<u32> = Vec::new();
-    if reminders_file.exists() {
-        debug!("Reading from reminders file");
-        let existing_reminders = match fs::read_to_string(reminders_file) {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Could not read from reminders.txt: {e}");
-                process::exit(1);
+    /// Records the appointments it's asked to send reminders for.
+    #[derive(Default)]
+    struct StubNotifier {
+        sent: Mutex<Vec<u32>>,
+    }
+
+    impl Notifier for &StubNotifier {
+        fn name(&self) -> &'static str {
+            "stub"
+        }
+
+        fn notify(&self, _customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+            self.sent.lock().unwrap().push(appointment.id);
+            Ok(())
+        }
+    }
+
+    /// A required channel that fails while `fail` is set.
+    #[derive(Default)]
+    struct FailingNotifier {
+        fail: std::sync::atomic::AtomicBool,
+    }
+
+    impl Notifier for &FailingNotifier {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn notify(&self, _customer: &CustomerInfo, _appointment: &Appointment) -> Result<()> {
+            if self.fail.load(Ordering::Relaxed) {
+                Err(anyhow!("failed"))
+            } else {
+                Ok(())
             }
+        }
+    }
+
+    /// Runtime for setting up the mock API; requests to it are made outside of it.
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Start a mock API serving the appointments and customers.
+    fn mock_api(runtime: &Runtime, appointments: Value, customers: Value) -> MockServer {
+        runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/appointments"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(appointments))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/customers"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(customers))
+                .mount(&server)
+                .await;
+            server
+        })
+    }
+
+    /// A config pointing at the mock API, with `extra` TOML added to it.
+    fn test_config(server: &MockServer, extra: &str) -> Config {
+        let mut config = Config::test_with(extra);
+        config.api_root = format!("{}/", server.uri());
+        config
+    }
+
+    fn appointment(id: u32, start: &str, customer_id: u32) -> Value {
+        json!({ "id": id, "start": start, "customerId": customer_id })
+    }
+
+    fn customer(id: u32) -> Value {
+        json!({
+            "id": id,
+            "firstName": "Jane",
+            "lastName": "Doe",
+            "email": "jane@example.com",
+        })
+    }
+
+    /// Run a single check at a fixed time, returning what was notified.
+    fn run_check(
+        appointments: Value,
+        customers: Value,
+        reminders_set: &mut HashSet<ReminderKey>,
+    ) -> Vec<u32> {
+        run_check_with("", appointments, customers, reminders_set)
+    }
+
+    /// Run a single check like `run_check`, with `extra` TOML added to the config.
+    ///
+    /// The appointments are in the order they were sent only if `max_concurrent_sends`
+    /// is 1; otherwise they're sorted by ID.
+    fn run_check_with(
+        extra: &str,
+        appointments: Value,
+        customers: Value,
+        reminders_set: &mut HashSet<ReminderKey>,
+    ) -> Vec<u32> {
+        let runtime = runtime();
+        let server = mock_api(&runtime, appointments, customers);
+        let config = test_config(&server, extra);
+        let stub = StubNotifier::default();
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(&stub)];
+        let now = Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap();
+        check(
+            &Client::new(),
+            &config,
+            &notifiers,
+            reminders_set,
+            &mut RetryQueue::default(),
+            &mut SendCounts::default(),
+            &mut CustomerCache::default(),
+            now,
+            false,
+        )
+        .unwrap();
+        drop(notifiers);
+        let mut sent = stub.sent.into_inner().unwrap();
+        // concurrent sends finish in any order
+        if config.max_concurrent_sends > 1 {
+            sent.sort_unstable();
+        }
+        sent
+    }
+
+    const WINDOW_SECS: u64 = 3 * 24 * 60 * 60;
+    const START: &str = "2024-01-11 12:00:00";
+
+    #[test]
+    fn lists_when_reminders_were_sent() {
+        let path = env::temp_dir().join("ea_reminders_list_test.txt");
+        std::fs::write(
+            &path,
+            format!(
+                "1,{WINDOW_SECS},{START},2024-01-10T12:00:00+00:00\n\
+                2,{WINDOW_SECS},2024-01-09 12:00:00,2024-01-08T12:05:00+00:00\n\
+                3,{WINDOW_SECS}"
+            ),
+        )
+        .unwrap();
+        let reminders = Persisted::load(Store::Text {
+            path: path.clone(),
+            legacy_offset: 0,
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let config = Config::test_with(
+            r#"
+            timezone = "America/New_York"
+            "#,
+        );
+        let mut retries = RetryQueue::default();
+        let given_up = [(5, WINDOW_SECS, Some(String::from(START)))];
+        retries.record(&given_up, 2);
+        retries.record(&given_up, 2);
+        let status = |id| {
+            let appointment: Appointment =
+                serde_json::from_value(appointment(id, START, 10)).unwrap();
+            reminded(&config, &reminders, &retries, &appointment)
         };
-        reminders_set.extend(
-            existing_reminders
-                .split_terminator('\n')
-                .map(|line| line.parse::<u32>().expect("Could not parse to int")),
+        assert_eq!(status(1), "yes, at 2024-01-10 07:00");
+        assert_eq!(
+            status(2),
+            "no, but sent at 2024-01-08 07:05 for 2024-01-09 12:00:00"
         );
-        info!(
-            "Loaded {} existing reminder IDs from file",
-            reminders_set.len()
+        assert_eq!(status(3), "yes");
+        assert_eq!(status(4), "no");
+        assert_eq!(status(5), "no, gave up after 2 attempts");
+    }
+
+    #[test]
+    fn notifies_soonest_appointments_first() {
+        let sent = run_check_with(
+            "max_concurrent_sends = 1",
+            json!([
+                appointment(1, "2024-01-12 09:00:00", 10),
+                appointment(2, "2024-01-10 15:00:00", 11),
+                appointment(3, START, 12),
+            ]),
+            json!([customer(10), customer(11), customer(12)]),
+            &mut HashSet::new(),
+        );
+        assert_eq!(sent, vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn defers_appointments_past_the_cycle_cap() {
+        let appointments = json!([
+            appointment(1, "2024-01-12 09:00:00", 10),
+            appointment(2, "2024-01-10 15:00:00", 11),
+            appointment(3, START, 12),
+        ]);
+        let customers = json!([customer(10), customer(11), customer(12)]);
+        let mut reminders_set = HashSet::new();
+        let extra = "max_appointments_per_cycle = 2";
+        let sent = run_check_with(
+            extra,
+            appointments.clone(),
+            customers.clone(),
+            &mut reminders_set,
+        );
+        assert_eq!(sent.into_iter().sorted().collect_vec(), vec![2, 3]);
+        let sent = run_check_with(extra, appointments, customers, &mut reminders_set);
+        assert_eq!(sent, vec![1]);
+    }
+
+    #[test]
+    fn prunes_old_reminders_the_api_no_longer_returns() {
+        let old = (2, WINDOW_SECS, Some(String::from("2023-12-01 12:00:00")));
+        let recent = (1, WINDOW_SECS, Some(String::from(START)));
+        let mut reminders_set = HashSet::from([old, recent.clone()]);
+        let sent = run_check_with(
+            "api_start_after_param = 'start_gte'",
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert!(sent.is_empty());
+        assert_eq!(reminders_set, HashSet::from([recent]));
+    }
+
+    #[test]
+    fn defers_whole_digests_past_the_cycle_cap() {
+        let appointments = json!([
+            appointment(1, "2024-01-12 09:00:00", 10),
+            appointment(2, "2024-01-10 15:00:00", 11),
+            appointment(3, START, 10),
+        ]);
+        let customers = json!([
+            customer(10),
+            json!({ "id": 11, "firstName": "John", "lastName": "Roe", "email": "john@example.com" }),
+        ]);
+        let mut reminders_set = HashSet::new();
+        let extra = "digest = true\nmax_appointments_per_cycle = 2";
+        let sent = run_check_with(
+            extra,
+            appointments.clone(),
+            customers.clone(),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![2]);
+        let sent = run_check_with(extra, appointments, customers, &mut reminders_set);
+        assert_eq!(sent, vec![1, 3]);
+    }
+
+    #[test]
+    fn notifies_appointments_in_window() {
+        let mut reminders_set = HashSet::new();
+        let sent = run_check(
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![1]);
+        assert_eq!(
+            reminders_set,
+            HashSet::from([(1, WINDOW_SECS, Some(String::from(START)))])
+        );
+    }
+
+    #[test]
+    fn stops_when_the_api_ignores_pagination() {
+        let runtime = runtime();
+        let server = mock_api(&runtime, json!([]), json!([customer(10), customer(11)]));
+        let client = Client::new();
+        // every page comes back full and the same
+        let config = test_config(&server, "page_size = 2");
+        let error = api::get_customers(&client, &config).unwrap_err();
+        assert!(error.to_string().contains("same results"), "{error}");
+        // more results than were asked for
+        let config = test_config(&server, "page_size = 1");
+        let error = api::get_customers(&client, &config).unwrap_err();
+        assert!(error.to_string().contains("2 results"), "{error}");
+        let config = test_config(&server, "page_size = 3");
+        assert_eq!(api::get_customers(&client, &config).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retries_rate_limited_requests() {
+        let runtime = runtime();
+        let server = runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/appointments"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/appointments"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(json!([appointment(1, START, 10)])),
+                )
+                .mount(&server)
+                .await;
+            server
+        });
+        let appointments =
+            get_appointments(&Client::new(), &test_config(&server, ""), Utc::now()).unwrap();
+        assert_eq!(appointments.len(), 1);
+    }
+
+    #[test]
+    fn filters_appointments_by_start() {
+        let runtime = runtime();
+        let server = runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/appointments"))
+                .and(query_param("start_gte", "2024-01-03 12:00:00"))
+                .and(query_param("start_lte", "2024-01-13 12:00:00"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(json!([appointment(1, START, 10)])),
+                )
+                .mount(&server)
+                .await;
+            server
+        });
+        let config = test_config(
+            &server,
+            "api_start_after_param = 'start_gte'\napi_start_before_param = 'start_lte'",
+        );
+        let now = Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap();
+        let appointments = get_appointments(&Client::new(), &config, now).unwrap();
+        assert_eq!(appointments.len(), 1);
+    }
+
+    #[test]
+    fn skips_past_and_far_future_appointments() {
+        let mut reminders_set = HashSet::new();
+        let sent = run_check(
+            json!([
+                appointment(1, "2024-01-09 12:00:00", 10),
+                appointment(2, "2024-01-20 12:00:00", 10),
+            ]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert!(sent.is_empty());
+        assert!(reminders_set.is_empty());
+    }
+
+    #[test]
+    fn notifies_just_started_appointments_within_grace() {
+        let mut reminders_set = HashSet::new();
+        let sent = run_check_with(
+            "past_grace_minutes = 5",
+            json!([
+                appointment(1, "2024-01-10 11:57:00", 10),
+                appointment(2, "2024-01-10 11:50:00", 10)
+            ]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![1]);
+    }
+
+    #[test]
+    fn skips_customers_with_invalid_emails() {
+        let mut blank = customer(11);
+        blank["email"] = json!("");
+        let mut malformed = customer(12);
+        malformed["email"] = json!("jane at example.com");
+        let sent = run_check(
+            json!([
+                appointment(1, START, 10),
+                appointment(2, START, 11),
+                appointment(3, START, 12)
+            ]),
+            json!([customer(10), blank, malformed]),
+            &mut HashSet::new(),
+        );
+        assert_eq!(sent, vec![1]);
+    }
+
+    #[test]
+    fn dedups_customers_by_email() {
+        let appointments = json!([appointment(1, START, 10), appointment(2, START, 11)]);
+        let customers = json!([customer(10), customer(11)]);
+        let sent = run_check(appointments.clone(), customers.clone(), &mut HashSet::new());
+        assert_eq!(sent, vec![1, 2]);
+        let sent = run_check_with(
+            "dedup_customers = 'email'",
+            appointments,
+            customers,
+            &mut HashSet::new(),
+        );
+        assert_eq!(sent, vec![1]);
+    }
+
+    #[test]
+    fn skips_appointments_for_missing_customers() {
+        let mut reminders_set = HashSet::new();
+        let sent = run_check(
+            json!([
+                appointment(1, START, 10),
+                appointment(2, "2024-01-11 13:00:00", 99),
+            ]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![1]);
+        assert_eq!(
+            reminders_set,
+            HashSet::from([(1, WINDOW_SECS, Some(String::from(START)))])
+        );
+    }
+
+    #[test]
+    fn skips_reminders_sent_by_older_versions() {
+        let mut reminders_set = HashSet::from([(1, WINDOW_SECS, None)]);
+        let sent = run_check(
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn notifies_rescheduled_appointments() {
+        let mut reminders_set =
+            HashSet::from([(1, WINDOW_SECS, Some(String::from("2024-01-11 09:00:00")))]);
+        let sent = run_check(
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![1]);
+        assert_eq!(
+            reminders_set,
+            HashSet::from([(1, WINDOW_SECS, Some(String::from(START)))])
+        );
+    }
+
+    #[test]
+    fn skips_reminders_already_sent() {
+        let mut reminders_set = HashSet::from([(1, WINDOW_SECS, Some(String::from(START)))]);
+        let sent = run_check(
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert!(sent.is_empty());
+        assert_eq!(
+            reminders_set,
+            HashSet::from([(1, WINDOW_SECS, Some(String::from(START)))])
+        );
+    }
+
+    #[test]
+    fn skips_appointments_on_holidays() {
+        let mut reminders_set = HashSet::new();
+        let sent = run_check_with(
+            "holidays = ['2024-01-11']",
+            json!([
+                appointment(1, START, 10),
+                appointment(2, "2024-01-12 12:00:00", 10)
+            ]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![2]);
+    }
+
+    #[test]
+    fn defers_reminders_outside_send_days() {
+        // the check runs on a Wednesday
+        let mut reminders_set = HashSet::new();
+        let sent = run_check_with(
+            "send_days = ['Mon', 'Tue']",
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert!(sent.is_empty());
+        assert!(reminders_set.is_empty());
+        let sent = run_check_with(
+            "send_days = ['Wed']",
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![1]);
+    }
+
+    #[test]
+    fn caps_reminders_per_customer_per_day() {
+        let mut reminders_set = HashSet::new();
+        let sent = run_check_with(
+            "max_reminders_per_customer_per_day = 1",
+            json!([
+                appointment(1, START, 10),
+                appointment(2, "2024-01-12 12:00:00", 10),
+                appointment(3, START, 11)
+            ]),
+            json!([customer(10), customer(11)]),
+            &mut reminders_set,
+        );
+        assert_eq!(sent, vec![1, 3]);
+        assert!(!reminders_set.iter().any(|(id, _, _)| *id == 2));
+    }
+
+    #[test]
+    fn caps_reminders_per_person_before_the_cycle_cap() {
+        let sent = run_check_with(
+            "max_reminders_per_customer_per_day = 1\n\
+            max_appointments_per_cycle = 2\n\
+            dedup_customers = 'email'",
+            json!([
+                appointment(1, START, 10),
+                appointment(2, "2024-01-11 15:00:00", 11),
+                appointment(3, "2024-01-12 12:00:00", 12)
+            ]),
+            json!([
+                customer(10),
+                customer(11),
+                json!({ "id": 12, "firstName": "John", "lastName": "Roe", "email": "john@example.com" }),
+            ]),
+            &mut HashSet::new(),
         );
+        assert_eq!(sent, vec![1, 3]);
+    }
+
+    #[test]
+    fn sends_a_single_appointment_on_demand() {
+        let runtime = runtime();
+        let server = mock_api(&runtime, json!([]), json!([]));
+        runtime.block_on(async {
+            Mock::given(method("GET"))
+                .and(path("/appointments/1"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(appointment(1, START, 10)))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/customers/10"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(customer(10)))
+                .mount(&server)
+                .await;
+        });
+        let config = test_config(&server, "");
+        let stub = StubNotifier::default();
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(&stub)];
+        let now = Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap();
+        let sent = send_now(&Client::new(), &config, &notifiers, 1, now).unwrap();
+        assert_eq!(sent, vec![(1, WINDOW_SECS, Some(String::from(START)))]);
+        drop(notifiers);
+        assert_eq!(stub.sent.into_inner().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn writes_reminder_notes_back() {
+        let runtime = runtime();
+        let mut appointment = appointment(1, START, 10);
+        appointment["notes"] = json!("Bring ID");
+        let server = mock_api(&runtime, json!([appointment]), json!([customer(10)]));
+        runtime.block_on(
+            Mock::given(method("PUT"))
+                .and(path("/appointments/1"))
+                .and(body_json(
+                    json!({ "notes": "Bring ID\nReminder sent on 2024-01-10" }),
+                ))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&server),
+        );
+        let config = test_config(&server, "write_back_reminder = true");
+        let stub = StubNotifier::default();
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(&stub)];
+        let report = check(
+            &Client::new(),
+            &config,
+            &notifiers,
+            &mut HashSet::new(),
+            &mut RetryQueue::default(),
+            &mut SendCounts::default(),
+            &mut CustomerCache::default(),
+            Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(report.sent, vec![1]);
+        runtime.block_on(server.verify());
     }
 
-    loop {
-        info!("Checking for reminders");
-        if let Err(e) = check(&config, &mut reminders_set) {
-            error!("Error processing potential reminders: {e}");
+    #[test]
+    fn resends_only_through_the_channels_that_failed() {
+        let runtime = runtime();
+        let server = mock_api(
+            &runtime,
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+        );
+        let config = test_config(&server, "");
+        let stub = StubNotifier::default();
+        let failing = FailingNotifier::default();
+        failing.fail.store(true, Ordering::Relaxed);
+        let mut reminders = Persisted::load(Store::Text {
+            path: env::temp_dir().join("ea_reminders_channels_check_test.txt"),
+            legacy_offset: 0,
+        })
+        .unwrap();
+        let run = |reminders: &mut Persisted| {
+            let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(&stub), Box::new(&failing)];
+            check(
+                &Client::new(),
+                &config,
+                &notifiers,
+                reminders,
+                &mut RetryQueue::default(),
+                &mut SendCounts::default(),
+                &mut CustomerCache::default(),
+                Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap(),
+                false,
+            )
+            .unwrap()
         };
-        if let Err(e) = fs::write(
-            reminders_file,
-            reminders_set.iter().map(|id| id.to_string()).join("\n"),
-        ) {
-            error!("Error writing to 'reminders.txt': {e}");
-        }
-        debug!("Sleeping for {} seconds", cli.loop_delay);
-        sleep(Duration::from_secs(cli.loop_delay));
+        let key = (1, WINDOW_SECS, Some(String::from(START)));
+        assert_eq!(run(&mut reminders).failed, vec![1]);
+        assert_eq!(run(&mut reminders).failed, vec![1]);
+        assert!(!reminders.contains(&key));
+        failing.fail.store(false, Ordering::Relaxed);
+        assert_eq!(run(&mut reminders).sent, vec![1]);
+        assert_eq!(
+            reminders.sent_for(1),
+            vec![(
+                &key,
+                Some(Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap())
+            )]
+        );
+        assert_eq!(*stub.sent.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn keeps_retry_attempts_through_quiet_hours() {
+        let runtime = runtime();
+        let server = mock_api(
+            &runtime,
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+        );
+        let config = test_config(
+            &server,
+            "retry_interval_seconds = 60\nretry_max_attempts = 2",
+        );
+        let quiet = test_config(
+            &server,
+            "retry_interval_seconds = 60\nquiet_hours_start = '11:00'\nquiet_hours_end = '13:00'",
+        );
+        let failing = FailingNotifier::default();
+        failing.fail.store(true, Ordering::Relaxed);
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(&failing)];
+        let key = (1, WINDOW_SECS, Some(String::from(START)));
+        let mut retries = RetryQueue::default();
+        let run = |config: &Config, retries: &mut RetryQueue| {
+            let report = check(
+                &Client::new(),
+                config,
+                &notifiers,
+                &mut HashSet::new(),
+                retries,
+                &mut SendCounts::default(),
+                &mut CustomerCache::default(),
+                Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap(),
+                false,
+            )
+            .unwrap();
+            record_retries(config, retries, &report);
+            report
+        };
+        assert_eq!(run(&config, &mut retries).failed, vec![1]);
+        assert!(run(&quiet, &mut retries).deferred);
+        assert_eq!(retries.len(), 1);
+        assert_eq!(run(&config, &mut retries).failed, vec![1]);
+        assert_eq!(retries.gave_up(&key), Some(2));
+    }
+
+    #[test]
+    fn skips_reminders_that_were_given_up_on() {
+        let runtime = runtime();
+        let server = mock_api(
+            &runtime,
+            json!([appointment(1, START, 10)]),
+            json!([customer(10)]),
+        );
+        let config = test_config(&server, "");
+        let stub = StubNotifier::default();
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(&stub)];
+        let key = (1, WINDOW_SECS, Some(String::from(START)));
+        let mut retries = RetryQueue::default();
+        retries.record(std::slice::from_ref(&key), 1);
+        let mut reminders_set = HashSet::new();
+        let report = check(
+            &Client::new(),
+            &config,
+            &notifiers,
+            &mut reminders_set,
+            &mut retries,
+            &mut SendCounts::default(),
+            &mut CustomerCache::default(),
+            Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap(),
+            false,
+        )
+        .unwrap();
+        assert!(report.sent.is_empty());
+        assert!(reminders_set.is_empty());
+        assert_eq!(retries.gave_up(&key), Some(1));
+    }
+
+    #[test]
+    fn jitter_stays_in_range_and_is_repeatable() {
+        let delays = |seed| {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            (0..100)
+                .map(|_| jittered(600, 60, &mut rng).as_secs())
+                .collect::<Vec<_>>()
+        };
+        let first = delays(7);
+        assert!(first.iter().all(|delay| (540..=660).contains(delay)));
+        assert_eq!(first, delays(7));
+        assert_eq!(
+            jittered(600, 0, &mut fastrand::Rng::new()),
+            Duration::from_secs(600)
+        );
+    }
+
+    #[test]
+    fn map_bounded_keeps_order() {
+        let items = (0..20).collect_vec();
+        for limit in [1, 3, 50] {
+            let squares = map_bounded(&items, limit, |n| {
+                thread::sleep(Duration::from_millis(20 - *n as u64));
+                n * n
+            });
+            assert_eq!(squares, items.iter().map(|n| n * n).collect_vec());
+        }
+        assert!(map_bounded(&Vec::<u32>::new(), 4, |n| *n).is_empty());
     }
 }
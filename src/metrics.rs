@@ -0,0 +1,51 @@
+use prometheus::{
+    register_int_counter, register_int_gauge, Encoder, IntCounter, IntGauge, TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// Reminders successfully sent.
+pub static REMINDERS_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("reminders_sent_total", "Reminders successfully sent").unwrap()
+});
+
+/// Failed requests to the Easy!Appointments API.
+pub static API_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "api_errors_total",
+        "Failed requests to the Easy!Appointments API"
+    )
+    .unwrap()
+});
+
+/// Reminder emails that could not be sent.
+pub static EMAILS_FAILED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "emails_failed_total",
+        "Reminder emails that could not be sent"
+    )
+    .unwrap()
+});
+
+/// When the last check finished.
+pub static LAST_CHECK: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "last_check_unix_timestamp",
+        "Unix timestamp of when the last check finished"
+    )
+    .unwrap()
+});
+
+/// Render all metrics in the Prometheus text format.
+pub fn render() -> String {
+    // make sure every metric is registered, even if it hasn't been used yet
+    LazyLock::force(&REMINDERS_SENT);
+    LazyLock::force(&API_ERRORS);
+    LazyLock::force(&EMAILS_FAILED);
+    LazyLock::force(&LAST_CHECK);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Could not encode metrics");
+    String::from_utf8(buffer).expect("Metrics were not valid UTF-8")
+}
@@ -0,0 +1,217 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    config::{Channel, Config},
+    discord::DiscordNotifier,
+    email::{EmailNotifier, Mailer},
+    slack::SlackNotifier,
+    sms::SmsNotifier,
+    telegram::TelegramNotifier,
+    webhook::WebhookNotifier,
+};
+use anyhow::{anyhow, Result};
+use log::warn;
+use reqwest::blocking::Client;
+use std::iter;
+
+/// A channel that reminders are sent through.
+///
+/// Reminders for different customers can be sent from several threads at once.
+pub trait Notifier: Sync {
+    /// Name of the channel, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Send the customer a reminder for the appointment.
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()>;
+
+    /// Send the customer a reminder covering several of their appointments.
+    ///
+    /// By default, a reminder is sent for each appointment separately.
+    fn notify_many(&self, customer: &CustomerInfo, appointments: &[&Appointment]) -> Result<()> {
+        appointments
+            .iter()
+            .try_for_each(|appointment| self.notify(customer, appointment))
+    }
+
+    /// Whether a failure means the reminder should be tried again on the next check.
+    ///
+    /// If so, the channels after it are skipped and the reminder isn't marked as sent, but
+    /// the channels it did go through are recorded, so it isn't sent through them again.
+    /// Otherwise the failure is only logged.
+    fn required(&self) -> bool {
+        true
+    }
+}
+
+/// Sends through a fallback channel when the primary channel fails.
+struct Fallback<'a> {
+    primary: Box<dyn Notifier + 'a>,
+    fallback: Box<dyn Notifier + 'a>,
+}
+
+impl Fallback<'_> {
+    /// Try `send` with the primary channel, and then with the fallback if that fails.
+    fn send(&self, send: impl Fn(&dyn Notifier) -> Result<()>) -> Result<()> {
+        send(self.primary.as_ref()).or_else(|e| {
+            warn!(
+                "Could not send {} reminder, trying {}: {e}",
+                self.primary.name(),
+                self.fallback.name()
+            );
+            send(self.fallback.as_ref()).map_err(|fallback_error| {
+                anyhow!(
+                    "{e}, and the {} fallback failed too: {fallback_error}",
+                    self.fallback.name()
+                )
+            })
+        })
+    }
+}
+
+impl Notifier for Fallback<'_> {
+    fn name(&self) -> &'static str {
+        self.primary.name()
+    }
+
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+        self.send(|notifier| notifier.notify(customer, appointment))
+    }
+
+    fn notify_many(&self, customer: &CustomerInfo, appointments: &[&Appointment]) -> Result<()> {
+        self.send(|notifier| notifier.notify_many(customer, appointments))
+    }
+
+    fn required(&self) -> bool {
+        self.primary.required()
+    }
+}
+
+/// Build a notifier for each channel that's set up in the config, starting with email.
+///
+/// If a `fallback_channel` is set, that channel is only used when an email can't be sent.
+///
+/// If `dry_run` is set, the notifiers log what they would send instead of sending it.
+pub fn from_config<'a>(
+    config: &'a Config,
+    client: &'a Client,
+    mailer: &'a Mailer,
+    dry_run: bool,
+) -> Vec<Box<dyn Notifier + 'a>> {
+    let mut others: Vec<(Channel, Box<dyn Notifier + 'a>)> = Vec::new();
+    if let Some(twilio) = config.twilio() {
+        others.push((
+            Channel::Sms,
+            Box::new(SmsNotifier::new(client, config, twilio, dry_run)),
+        ));
+    }
+    if let Some(url) = &config.webhook_url {
+        others.push((
+            Channel::Webhook,
+            Box::new(WebhookNotifier::new(client, url, dry_run)),
+        ));
+    }
+    if let Some(url) = &config.discord_webhook_url {
+        others.push((
+            Channel::Discord,
+            Box::new(DiscordNotifier::new(client, config, url, dry_run)),
+        ));
+    }
+    if let Some(telegram) = config.telegram() {
+        others.push((
+            Channel::Telegram,
+            Box::new(TelegramNotifier::new(client, config, telegram, dry_run)),
+        ));
+    }
+    if let Some(url) = &config.slack_webhook_url {
+        others.push((
+            Channel::Slack,
+            Box::new(SlackNotifier::new(client, config, url, dry_run)),
+        ));
+    }
+
+    let email: Box<dyn Notifier + 'a> = Box::new(EmailNotifier::new(config, mailer, dry_run));
+    let fallback = config.fallback_channel.and_then(|channel| {
+        let index = others.iter().position(|(c, _)| *c == channel)?;
+        Some(others.remove(index).1)
+    });
+    let primary = match fallback {
+        Some(fallback) => Box::new(Fallback {
+            primary: email,
+            fallback,
+        }),
+        None => email,
+    };
+    iter::once(primary)
+        .chain(others.into_iter().map(|(_, notifier)| notifier))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Counts its reminders, optionally failing every one.
+    struct Counting {
+        sent: AtomicU32,
+        fail: bool,
+    }
+
+    impl Notifier for &Counting {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn notify(&self, _customer: &CustomerInfo, _appointment: &Appointment) -> Result<()> {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+            if self.fail {
+                Err(anyhow!("failed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn notify(primary: &Counting, fallback: &Counting) -> Result<()> {
+        let customer = serde_json::from_value(serde_json::json!({
+            "id": 1, "firstName": "Jane", "lastName": "Doe", "email": "jane@example.com",
+        }))?;
+        let appointment = serde_json::from_value(serde_json::json!({
+            "id": 1, "start": "2024-01-11 12:00:00", "customerId": 1,
+        }))?;
+        Fallback {
+            primary: Box::new(primary),
+            fallback: Box::new(fallback),
+        }
+        .notify(&customer, &appointment)
+    }
+
+    #[test]
+    fn fallback_is_only_used_when_primary_fails() {
+        let counting = |fail| Counting {
+            sent: AtomicU32::new(0),
+            fail,
+        };
+        let (primary, fallback) = (counting(false), counting(false));
+        assert!(notify(&primary, &fallback).is_ok());
+        assert_eq!(
+            (
+                primary.sent.load(Ordering::Relaxed),
+                fallback.sent.load(Ordering::Relaxed)
+            ),
+            (1, 0)
+        );
+
+        let (primary, fallback) = (counting(true), counting(false));
+        assert!(notify(&primary, &fallback).is_ok());
+        assert_eq!(
+            (
+                primary.sent.load(Ordering::Relaxed),
+                fallback.sent.load(Ordering::Relaxed)
+            ),
+            (1, 1)
+        );
+
+        let (primary, fallback) = (counting(true), counting(true));
+        assert!(notify(&primary, &fallback).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::store::ReminderKey;
+use itertools::Itertools;
+use std::{collections::HashMap, time::Duration};
+
+/// Reminders that couldn't be sent, and how many times each has been tried.
+///
+/// Reminders that have been given up on are kept, with how many attempts were made, so
+/// they aren't tried again. The queue is saved in the reminders store, so it survives a
+/// restart.
+#[derive(Debug, Default, PartialEq)]
+pub struct RetryQueue {
+    attempts: HashMap<ReminderKey, u32>,
+    given_up: HashMap<ReminderKey, u32>,
+}
+
+impl RetryQueue {
+    /// Update the queue after a check, counting another attempt for each reminder that
+    /// `failed` and dropping the rest, which were either sent or are no longer due.
+    ///
+    /// Returns the reminders that have now been tried `max_attempts` times, which are
+    /// given up on.
+    pub fn record(&mut self, failed: &[ReminderKey], max_attempts: u32) -> Vec<(ReminderKey, u32)> {
+        let mut attempts = HashMap::new();
+        let mut exhausted = Vec::new();
+        for key in failed {
+            let count = self.attempts.get(key).copied().unwrap_or(0) + 1;
+            if count >= max_attempts {
+                self.given_up.insert(key.clone(), count);
+                exhausted.push((key.clone(), count));
+            } else {
+                attempts.insert(key.clone(), count);
+            }
+        }
+        self.attempts = attempts;
+        exhausted
+    }
+
+    /// How many attempts were made at the reminder, if it has been given up on.
+    pub fn gave_up(&self, key: &ReminderKey) -> Option<u32> {
+        self.given_up.get(key).copied()
+    }
+
+    /// Forget the reminders that `keep` returns false for, whether they're waiting or
+    /// have been given up on.
+    pub fn retain(&mut self, keep: &mut dyn FnMut(&ReminderKey) -> bool) {
+        self.attempts.retain(|key, _| keep(key));
+        self.given_up.retain(|key, _| keep(key));
+    }
+
+    /// How many reminders are waiting to be retried.
+    pub fn len(&self) -> usize {
+        self.attempts.len()
+    }
+
+    /// How long to wait before retrying, starting at `base` and doubling for each
+    /// attempt after the first; `None` if there's nothing to retry.
+    pub fn delay(&self, base: Duration) -> Option<Duration> {
+        let attempts = self.attempts.values().max()?;
+        Some(base.saturating_mul(2u32.saturating_pow(attempts - 1)))
+    }
+
+    /// Build the queue from `(reminder, attempts, given up)` rows.
+    pub fn from_rows(rows: impl IntoIterator<Item = (ReminderKey, u32, bool)>) -> Self {
+        let mut queue = Self::default();
+        for (key, count, given_up) in rows {
+            if given_up {
+                queue.given_up.insert(key, count);
+            } else {
+                queue.attempts.insert(key, count);
+            }
+        }
+        queue
+    }
+
+    /// The queue as `(reminder, attempts, given up)` rows, in sorted order.
+    pub fn rows(&self) -> Vec<(&ReminderKey, u32, bool)> {
+        self.attempts
+            .iter()
+            .map(|(key, count)| (key, *count, false))
+            .chain(self.given_up.iter().map(|(key, count)| (key, *count, true)))
+            .sorted()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_and_gives_up() {
+        let key = (1, 3600, Some(String::from("2024-01-11 12:00:00")));
+        let base = Duration::from_secs(60);
+        let failed = vec![key.clone()];
+        let mut queue = RetryQueue::default();
+        assert_eq!(queue.delay(base), None);
+        assert!(queue.record(&failed, 3).is_empty());
+        assert_eq!(queue.delay(base), Some(Duration::from_secs(60)));
+        assert!(queue.record(&failed, 3).is_empty());
+        assert_eq!(queue.delay(base), Some(Duration::from_secs(120)));
+        assert_eq!(queue.record(&failed, 3), vec![(key.clone(), 3)]);
+        assert_eq!(queue.delay(base), None);
+        assert_eq!(queue.gave_up(&key), Some(3));
+
+        // a reminder that's sent is dropped from the queue
+        let other = vec![(2, 3600, Some(String::from("2024-01-11 12:00:00")))];
+        queue.record(&other, 3);
+        queue.record(&[], 3);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.gave_up(&key), Some(3));
+    }
+}
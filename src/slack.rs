@@ -0,0 +1,63 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    config::Config,
+    notify::Notifier,
+    template::{render_template, AppointmentValues},
+};
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use reqwest::blocking::Client;
+use serde_json::json;
+
+/// Post a message to a Slack Incoming Webhook.
+pub fn send_slack(client: &Client, url: &str, text: &str) -> Result<()> {
+    let resp = client.post(url).json(&json!({ "text": text })).send()?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Got status {} from Slack webhook",
+            resp.status().as_u16()
+        ));
+    }
+    debug!("Slack notification sent");
+    Ok(())
+}
+
+/// Posts each reminder to a Slack channel, such as for staff.
+pub struct SlackNotifier<'a> {
+    client: &'a Client,
+    config: &'a Config,
+    url: &'a str,
+    dry_run: bool,
+}
+
+impl<'a> SlackNotifier<'a> {
+    /// If `dry_run` is set, messages are logged instead of posted.
+    pub fn new(client: &'a Client, config: &'a Config, url: &'a str, dry_run: bool) -> Self {
+        Self {
+            client,
+            config,
+            url,
+            dry_run,
+        }
+    }
+}
+
+impl Notifier for SlackNotifier<'_> {
+    fn name(&self) -> &'static str {
+        "Slack"
+    }
+
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+        let values = AppointmentValues::new(appointment, customer, self.config)?;
+        let text = render_template(&self.config.slack_message, customer, &values);
+        if self.dry_run {
+            info!("Dry run: would post to Slack:\n{text}");
+            return Ok(());
+        }
+        send_slack(self.client, self.url, &text)
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    config::{Channel, Config},
+    notify::Notifier,
+    template::{render_template, AppointmentValues},
+};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use reqwest::blocking::Client;
+
+/// Twilio account credentials and the number to send from.
+pub struct Twilio<'a> {
+    pub sid: &'a str,
+    pub token: &'a str,
+    pub from: &'a str,
+}
+
+/// Send an SMS through Twilio's REST API.
+pub fn send_sms(client: &Client, twilio: &Twilio, to: &str, body: &str) -> Result<()> {
+    let resp = client
+        .post(format!(
+            "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+            twilio.sid
+        ))
+        .basic_auth(twilio.sid, Some(twilio.token))
+        .form(&[("To", to), ("From", twilio.from), ("Body", body)])
+        .send()?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Got status {} from Twilio API",
+            resp.status().as_u16()
+        ));
+    }
+    debug!("SMS notification sent");
+    Ok(())
+}
+
+/// Sends reminders by SMS, to customers that have a phone number.
+pub struct SmsNotifier<'a> {
+    client: &'a Client,
+    config: &'a Config,
+    twilio: Twilio<'a>,
+    dry_run: bool,
+}
+
+impl<'a> SmsNotifier<'a> {
+    /// If `dry_run` is set, messages are logged instead of sent.
+    pub fn new(client: &'a Client, config: &'a Config, twilio: Twilio<'a>, dry_run: bool) -> Self {
+        Self {
+            client,
+            config,
+            twilio,
+            dry_run,
+        }
+    }
+}
+
+impl Notifier for SmsNotifier<'_> {
+    fn name(&self) -> &'static str {
+        "SMS"
+    }
+
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+        let Some(phone) = customer.phone.as_deref().filter(|phone| !phone.is_empty()) else {
+            // as the fallback, it's the last chance to reach the customer, so that's a failure
+            if self.config.fallback_channel == Some(Channel::Sms) {
+                return Err(anyhow!("No phone number for customer {}", customer.id));
+            }
+            warn!(
+                customer_id = customer.id;
+                "Not sending an SMS to customer {}, who has no phone number",
+                customer.id
+            );
+            return Ok(());
+        };
+        let values = AppointmentValues::new(appointment, customer, self.config)?;
+        let template = self
+            .config
+            .sms_body
+            .as_ref()
+            .unwrap_or(&self.config.email_body);
+        let body = render_template(template, customer, &values);
+        if self.dry_run {
+            info!("Dry run: would send SMS to {phone}:\n{body}");
+            return Ok(());
+        }
+        send_sms(self.client, &self.twilio, phone, &body)
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+}
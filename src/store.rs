@@ -0,0 +1,937 @@
+use crate::retry::RetryQueue;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use itertools::Itertools;
+use log::{info, warn};
+use rusqlite::{params, types::Value as SqlValue, Connection, OptionalExtension};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const TEXT_FILE_NAME: &str = "reminders.txt";
+const DB_FILE_NAME: &str = "reminders.db";
+
+/// A sent reminder: the appointment ID, the reminder offset in seconds, and the
+/// appointment's start when it was sent.
+///
+/// The start is `None` for reminders recorded by older versions, which didn't store it.
+pub type ReminderKey = (u32, u64, Option<String>);
+
+/// When each reminder was sent, for those it's known for.
+pub type SentTimes = HashMap<ReminderKey, DateTime<Utc>>;
+
+/// The reminders that have been sent, which `check` reads and updates.
+pub trait ReminderStore {
+    /// Whether the reminder has been sent.
+    fn contains(&self, key: &ReminderKey) -> bool;
+
+    /// Record that the reminder was sent at `sent_at`, through every channel.
+    fn mark(&mut self, key: ReminderKey, sent_at: DateTime<Utc>);
+
+    /// Whether the reminder has been sent through `channel`, when it couldn't be sent
+    /// through all of them.
+    fn sent_through(&self, key: &ReminderKey, channel: &str) -> bool;
+
+    /// Record that the reminder has been sent through `channel`, but not through all of
+    /// them yet, so it's only tried again through the others.
+    fn mark_channel(&mut self, key: ReminderKey, channel: &str);
+
+    /// Forget the reminders that `keep` returns false for.
+    fn retain(&mut self, keep: &mut dyn FnMut(&ReminderKey) -> bool);
+
+    /// How many reminders have been sent.
+    fn len(&self) -> usize;
+
+    /// The reminders sent for an appointment, whatever its start was at the time, with when
+    /// each was sent if that's known.
+    fn sent_for(&self, appointment_id: u32) -> Vec<(&ReminderKey, Option<DateTime<Utc>>)>;
+
+    /// Write out the changes since the last flush, if the store is persistent.
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// An in-memory store, which forgets everything when it's dropped.
+///
+/// It doesn't keep track of channels, so a reminder that couldn't be sent through all of
+/// them is tried again through every one.
+impl ReminderStore for HashSet<ReminderKey> {
+    fn contains(&self, key: &ReminderKey) -> bool {
+        HashSet::contains(self, key)
+    }
+
+    fn mark(&mut self, key: ReminderKey, _sent_at: DateTime<Utc>) {
+        self.insert(key);
+    }
+
+    fn sent_through(&self, _key: &ReminderKey, _channel: &str) -> bool {
+        false
+    }
+
+    fn mark_channel(&mut self, _key: ReminderKey, _channel: &str) {}
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&ReminderKey) -> bool) {
+        HashSet::retain(self, keep);
+    }
+
+    fn len(&self) -> usize {
+        HashSet::len(self)
+    }
+
+    fn sent_for(&self, appointment_id: u32) -> Vec<(&ReminderKey, Option<DateTime<Utc>>)> {
+        self.iter()
+            .filter(|key| key.0 == appointment_id)
+            .map(|key| (key, None))
+            .collect()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The channels that reminders have been sent through, for those that couldn't be sent
+/// through all of them.
+pub type SentChannels = HashSet<(ReminderKey, String)>;
+
+/// The sent reminders from a `Store`, kept in memory and saved back to it on `flush`.
+pub struct Persisted {
+    store: Store,
+    reminders: HashSet<ReminderKey>,
+    sent_at: SentTimes,
+    channels: SentChannels,
+}
+
+impl Persisted {
+    /// Load the reminders, when they were sent, and the channels of those only partly
+    /// sent, from the store.
+    pub fn load(store: Store) -> Result<Self> {
+        let (reminders, sent_at) = store.load()?;
+        let channels = store.load_channels()?;
+        Ok(Self {
+            store,
+            reminders,
+            sent_at,
+            channels,
+        })
+    }
+
+    /// The store that the reminders are saved to.
+    pub fn store_mut(&mut self) -> &mut Store {
+        &mut self.store
+    }
+}
+
+impl ReminderStore for Persisted {
+    fn contains(&self, key: &ReminderKey) -> bool {
+        self.reminders.contains(key)
+    }
+
+    fn mark(&mut self, key: ReminderKey, sent_at: DateTime<Utc>) {
+        self.channels.retain(|(sent, _)| *sent != key);
+        self.sent_at.entry(key.clone()).or_insert(sent_at);
+        self.reminders.insert(key);
+    }
+
+    fn sent_through(&self, key: &ReminderKey, channel: &str) -> bool {
+        self.channels
+            .contains(&(key.clone(), String::from(channel)))
+    }
+
+    fn mark_channel(&mut self, key: ReminderKey, channel: &str) {
+        self.channels.insert((key, String::from(channel)));
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&ReminderKey) -> bool) {
+        self.reminders.retain(&mut *keep);
+        self.sent_at.retain(|key, _| self.reminders.contains(key));
+        self.channels.retain(|(key, _)| keep(key));
+    }
+
+    fn len(&self) -> usize {
+        self.reminders.len()
+    }
+
+    fn sent_for(&self, appointment_id: u32) -> Vec<(&ReminderKey, Option<DateTime<Utc>>)> {
+        self.reminders
+            .iter()
+            .filter(|key| key.0 == appointment_id)
+            .map(|key| (key, self.sent_at.get(key).copied()))
+            .collect()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.store.save(&self.reminders, &self.sent_at)?;
+        self.store.save_channels(&self.channels)
+    }
+}
+
+/// How many reminders each customer has been sent on the current local day.
+///
+/// Customers are counted by their `customer_key`, so with `dedup_customers = 'email'`
+/// every customer record with the same address shares a count.
+#[derive(Debug, Default, PartialEq)]
+pub struct SendCounts {
+    day: Option<NaiveDate>,
+    counts: HashMap<String, u32>,
+}
+
+impl SendCounts {
+    /// How many reminders `customer` has been sent on `day`.
+    pub fn get(&self, customer: &str, day: NaiveDate) -> u32 {
+        if self.day != Some(day) {
+            return 0;
+        }
+        self.counts.get(customer).copied().unwrap_or(0)
+    }
+
+    /// Count a reminder sent to `customer` on `day`, forgetting any earlier day.
+    pub fn add(&mut self, customer: &str, day: NaiveDate) {
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.counts.clear();
+        }
+        *self.counts.entry(customer.to_owned()).or_default() += 1;
+    }
+
+    /// Build the counts from `(day, customer, count)` rows, keeping only the latest day.
+    fn from_rows(rows: impl IntoIterator<Item = (NaiveDate, String, u32)>) -> Self {
+        let rows = rows.into_iter().collect_vec();
+        let day = rows.iter().map(|(day, _, _)| *day).max();
+        let counts = rows
+            .into_iter()
+            .filter(|(row_day, _, _)| Some(*row_day) == day)
+            .map(|(_, customer, count)| (customer, count))
+            .collect();
+        Self { day, counts }
+    }
+}
+
+/// Which backend to persist sent reminders to.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// A SQLite database, "reminders.db" by default.
+    #[default]
+    Sqlite,
+    /// A plain text file, "reminders.txt" by default.
+    Text,
+}
+
+impl StoreKind {
+    /// The store path for one of several instances: `path`, or the default file name, with
+    /// `-<instance>` added to the name, like "reminders-north.db".
+    pub fn instance_path(self, path: Option<&Path>, instance: &str) -> PathBuf {
+        let path = path.unwrap_or(Path::new(match self {
+            Self::Sqlite => DB_FILE_NAME,
+            Self::Text => TEXT_FILE_NAME,
+        }));
+        let mut name = path.file_stem().unwrap_or_default().to_owned();
+        name.push(format!("-{instance}"));
+        if let Some(extension) = path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        path.with_file_name(name)
+    }
+}
+
+/// Persistent record of which reminders have been sent.
+pub enum Store {
+    Text { path: PathBuf, legacy_offset: u64 },
+    Sqlite(Connection),
+}
+
+impl Store {
+    /// Open the store, creating it if needed.
+    ///
+    /// `legacy_offset` is used for entries written by older versions, which only
+    /// recorded the appointment ID.
+    ///
+    /// `path` defaults to "reminders.db" or "reminders.txt" in the working directory.
+    /// When opening a new SQLite store, any existing reminders from the text file with the
+    /// same name, like "reminders.txt" for "reminders.db", are migrated into it.
+    pub fn open(kind: StoreKind, path: Option<&Path>, legacy_offset: u64) -> Result<Self> {
+        match kind {
+            StoreKind::Text => Ok(Self::Text {
+                path: path.unwrap_or(Path::new(TEXT_FILE_NAME)).to_owned(),
+                legacy_offset,
+            }),
+            StoreKind::Sqlite => {
+                let db_path = path.unwrap_or(Path::new(DB_FILE_NAME));
+                let text_path = db_path.with_extension("txt");
+                let is_new = !db_path.exists();
+                let conn = Connection::open(db_path)?;
+                // reminders without a start are stored with an empty one, so they're still
+                // unique in the primary key
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS reminders (
+                        appointment_id INTEGER NOT NULL,
+                        offset_secs INTEGER NOT NULL,
+                        sent_at TEXT NOT NULL,
+                        start TEXT NOT NULL DEFAULT '',
+                        PRIMARY KEY (appointment_id, offset_secs, start)
+                    )",
+                    (),
+                )?;
+                let start_key: Option<i64> = conn
+                    .query_row(
+                        "SELECT pk FROM pragma_table_info('reminders') WHERE name = 'start'",
+                        (),
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if start_key.unwrap_or(0) == 0 {
+                    migrate_start_key(&conn, start_key.is_some())?;
+                }
+                conn.execute(
+                    // customer_id holds the customer key, which is an email address
+                    // with dedup_customers = 'email'
+                    "CREATE TABLE IF NOT EXISTS send_counts (
+                        day TEXT NOT NULL,
+                        customer_id INTEGER NOT NULL,
+                        count INTEGER NOT NULL,
+                        PRIMARY KEY (day, customer_id)
+                    )",
+                    (),
+                )?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS channels (
+                        appointment_id INTEGER NOT NULL,
+                        offset_secs INTEGER NOT NULL,
+                        start TEXT,
+                        channel TEXT NOT NULL
+                    )",
+                    (),
+                )?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS retries (
+                        appointment_id INTEGER NOT NULL,
+                        offset_secs INTEGER NOT NULL,
+                        start TEXT,
+                        attempts INTEGER NOT NULL,
+                        given_up INTEGER NOT NULL
+                    )",
+                    (),
+                )?;
+                let mut store = Self::Sqlite(conn);
+                if is_new && text_path.exists() {
+                    let (existing, sent_at) = load_text(&text_path, legacy_offset)?;
+                    store.save(&existing, &sent_at)?;
+                    info!(
+                        "Migrated {} reminders from {} to {}",
+                        existing.len(),
+                        text_path.display(),
+                        db_path.display()
+                    );
+                }
+                Ok(store)
+            }
+        }
+    }
+
+    /// Load all sent reminders, and when they were sent.
+    pub fn load(&self) -> Result<(HashSet<ReminderKey>, SentTimes)> {
+        match self {
+            Self::Text {
+                path,
+                legacy_offset,
+            } => {
+                if !path.exists() {
+                    return Ok(Default::default());
+                }
+                load_text(path, *legacy_offset)
+            }
+            Self::Sqlite(conn) => {
+                let mut stmt = conn
+                    .prepare("SELECT appointment_id, offset_secs, start, sent_at FROM reminders")?;
+                let rows = stmt.query_map((), |row| {
+                    Ok((
+                        (row.get(0)?, row.get(1)?, start_from_row(row.get(2)?)),
+                        row.get::<_, String>(3)?,
+                    ))
+                })?;
+                let mut reminders = HashSet::new();
+                let mut sent_at = SentTimes::new();
+                for row in rows {
+                    let (key, sent): (ReminderKey, _) = row?;
+                    if let Ok(sent) = DateTime::parse_from_rfc3339(&sent) {
+                        sent_at.insert(key.clone(), sent.to_utc());
+                    }
+                    reminders.insert(key);
+                }
+                Ok((reminders, sent_at))
+            }
+        }
+    }
+
+    /// Persist the sent reminders, replacing what was stored before.
+    ///
+    /// The text file is written in sorted order, so saving what was loaded leaves it
+    /// unchanged. For SQLite, reminders that are already stored keep their original `sent_at`.
+    /// Reminders without a time in `sent_at` are stored as sent now.
+    pub fn save(&mut self, reminders: &HashSet<ReminderKey>, sent_at: &SentTimes) -> Result<()> {
+        match self {
+            Self::Text { path, .. } => {
+                write_atomic(
+                    path,
+                    &reminders
+                        .iter()
+                        .sorted()
+                        .map(|key| {
+                            let (id, offset, start) = key;
+                            match (start, sent_at.get(key)) {
+                                (start, Some(sent)) => format!(
+                                    "{id},{offset},{},{}",
+                                    start.as_deref().unwrap_or_default(),
+                                    sent.to_rfc3339()
+                                ),
+                                (Some(start), None) => format!("{id},{offset},{start}"),
+                                (None, None) => format!("{id},{offset}"),
+                            }
+                        })
+                        .join("\n"),
+                )?;
+            }
+            Self::Sqlite(conn) => {
+                let now = Utc::now().to_rfc3339();
+                let tx = conn.transaction()?;
+                {
+                    let stored = tx
+                        .prepare("SELECT appointment_id, offset_secs, start FROM reminders")?
+                        .query_map((), |row| {
+                            Ok((row.get(0)?, row.get(1)?, start_from_row(row.get(2)?)))
+                        })?
+                        .collect::<Result<Vec<ReminderKey>, _>>()?;
+                    let mut delete = tx.prepare(
+                        "DELETE FROM reminders
+                        WHERE appointment_id = ?1 AND offset_secs = ?2 AND start = ?3",
+                    )?;
+                    for (id, offset, start) in stored.iter().filter(|key| !reminders.contains(key))
+                    {
+                        delete.execute(params![
+                            id,
+                            offset,
+                            start.as_deref().unwrap_or_default()
+                        ])?;
+                    }
+                    let mut stmt = tx.prepare(
+                        "INSERT OR IGNORE INTO reminders (appointment_id, offset_secs, sent_at, start)
+                        VALUES (?1, ?2, ?3, ?4)",
+                    )?;
+                    for key in reminders {
+                        let (id, offset, start) = key;
+                        let sent = sent_at.get(key).map(DateTime::to_rfc3339);
+                        stmt.execute(params![
+                            id,
+                            offset,
+                            sent.as_ref().unwrap_or(&now),
+                            start.as_deref().unwrap_or_default()
+                        ])?;
+                    }
+                }
+                tx.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load how many reminders each customer has been sent today.
+    ///
+    /// The text store keeps these in `<path>.counts`, one `<day>,<customer key>,<count>`
+    /// line per customer.
+    pub fn load_send_counts(&self) -> Result<SendCounts> {
+        match self {
+            Self::Text { path, .. } => {
+                let path = counts_path(path);
+                if !path.exists() {
+                    return Ok(SendCounts::default());
+                }
+                let text = fs::read_to_string(&path)?;
+                Ok(SendCounts::from_rows(text.lines().filter_map(|line| {
+                    // an email address could have a comma in it, so split from both ends
+                    let (day, rest) = line.trim().split_once(',')?;
+                    let (customer, count) = rest.rsplit_once(',')?;
+                    Some((day.parse().ok()?, customer.to_owned(), count.parse().ok()?))
+                })))
+            }
+            Self::Sqlite(conn) => {
+                let mut stmt = conn.prepare("SELECT day, customer_id, count FROM send_counts")?;
+                let rows = stmt
+                    .query_map((), |row| {
+                        Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(SendCounts::from_rows(rows.into_iter().filter_map(
+                    |(day, customer, count)| {
+                        // the column has integer affinity, so customer IDs come back as numbers
+                        let customer = match customer {
+                            SqlValue::Integer(id) => id.to_string(),
+                            SqlValue::Text(key) => key,
+                            _ => return None,
+                        };
+                        Some((day.parse().ok()?, customer, count))
+                    },
+                )))
+            }
+        }
+    }
+
+    /// Persist the per-customer counts, replacing what was stored before.
+    pub fn save_send_counts(&mut self, counts: &SendCounts) -> Result<()> {
+        let rows = counts
+            .counts
+            .iter()
+            .sorted()
+            .filter_map(|(customer, count)| Some((counts.day?, customer, count)))
+            .collect_vec();
+        match self {
+            Self::Text { path, .. } => {
+                write_atomic(
+                    &counts_path(path),
+                    &rows
+                        .iter()
+                        .map(|(day, customer, count)| format!("{day},{customer},{count}"))
+                        .join("\n"),
+                )?;
+            }
+            Self::Sqlite(conn) => {
+                let tx = conn.transaction()?;
+                tx.execute("DELETE FROM send_counts", ())?;
+                {
+                    let mut stmt = tx.prepare(
+                        "INSERT INTO send_counts (day, customer_id, count) VALUES (?1, ?2, ?3)",
+                    )?;
+                    for (day, customer, count) in rows {
+                        stmt.execute(params![day.to_string(), customer, count])?;
+                    }
+                }
+                tx.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the channels that reminders were sent through, for those that couldn't be sent
+    /// through all of them.
+    ///
+    /// The text store keeps these in `<path>.channels`, one
+    /// `<appointment id>,<offset seconds>,<channel>,<start>` line per channel.
+    pub fn load_channels(&self) -> Result<SentChannels> {
+        match self {
+            Self::Text { path, .. } => {
+                let path = channels_path(path);
+                if !path.exists() {
+                    return Ok(SentChannels::default());
+                }
+                let text = fs::read_to_string(&path)?;
+                Ok(text
+                    .lines()
+                    .filter_map(|line| {
+                        let (id, offset, channel, start) =
+                            line.trim().splitn(4, ',').collect_tuple()?;
+                        let start = Some(start).filter(|s| !s.is_empty()).map(String::from);
+                        Some((
+                            (id.parse().ok()?, offset.parse().ok()?, start),
+                            String::from(channel),
+                        ))
+                    })
+                    .collect())
+            }
+            Self::Sqlite(conn) => {
+                let mut stmt = conn
+                    .prepare("SELECT appointment_id, offset_secs, start, channel FROM channels")?;
+                let rows = stmt
+                    .query_map((), |row| {
+                        Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(rows)
+            }
+        }
+    }
+
+    /// Persist the channels of partly sent reminders, replacing what was stored before.
+    pub fn save_channels(&mut self, channels: &SentChannels) -> Result<()> {
+        let rows = channels.iter().sorted().collect_vec();
+        match self {
+            Self::Text { path, .. } => {
+                write_atomic(
+                    &channels_path(path),
+                    &rows
+                        .iter()
+                        .map(|((id, offset, start), channel)| {
+                            format!(
+                                "{id},{offset},{channel},{}",
+                                start.as_deref().unwrap_or_default()
+                            )
+                        })
+                        .join("\n"),
+                )?;
+            }
+            Self::Sqlite(conn) => {
+                let tx = conn.transaction()?;
+                tx.execute("DELETE FROM channels", ())?;
+                {
+                    let mut stmt = tx.prepare(
+                        "INSERT INTO channels (appointment_id, offset_secs, start, channel)
+                        VALUES (?1, ?2, ?3, ?4)",
+                    )?;
+                    for ((id, offset, start), channel) in rows {
+                        stmt.execute(params![id, offset, start, channel])?;
+                    }
+                }
+                tx.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the reminders that couldn't be sent, and the ones that were given up on.
+    ///
+    /// The text store keeps these in `<path>.retries`, one
+    /// `<appointment id>,<offset seconds>,<attempts>,<given up>,<start>` line per reminder.
+    pub fn load_retries(&self) -> Result<RetryQueue> {
+        match self {
+            Self::Text { path, .. } => {
+                let path = retries_path(path);
+                if !path.exists() {
+                    return Ok(RetryQueue::default());
+                }
+                let text = fs::read_to_string(&path)?;
+                Ok(RetryQueue::from_rows(text.lines().filter_map(|line| {
+                    let (id, offset, attempts, given_up, start) =
+                        line.trim().splitn(5, ',').collect_tuple()?;
+                    let start = Some(start).filter(|s| !s.is_empty()).map(String::from);
+                    Some((
+                        (id.parse().ok()?, offset.parse().ok()?, start),
+                        attempts.parse().ok()?,
+                        given_up.parse().ok()?,
+                    ))
+                })))
+            }
+            Self::Sqlite(conn) => {
+                let mut stmt = conn.prepare(
+                    "SELECT appointment_id, offset_secs, start, attempts, given_up FROM retries",
+                )?;
+                let rows = stmt
+                    .query_map((), |row| {
+                        Ok((
+                            (row.get(0)?, row.get(1)?, row.get(2)?),
+                            row.get(3)?,
+                            row.get(4)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(RetryQueue::from_rows(rows))
+            }
+        }
+    }
+
+    /// Persist the retry queue, replacing what was stored before.
+    pub fn save_retries(&mut self, retries: &RetryQueue) -> Result<()> {
+        let rows = retries.rows();
+        match self {
+            Self::Text { path, .. } => {
+                write_atomic(
+                    &retries_path(path),
+                    &rows
+                        .iter()
+                        .map(|((id, offset, start), attempts, given_up)| {
+                            format!(
+                                "{id},{offset},{attempts},{given_up},{}",
+                                start.as_deref().unwrap_or_default()
+                            )
+                        })
+                        .join("\n"),
+                )?;
+            }
+            Self::Sqlite(conn) => {
+                let tx = conn.transaction()?;
+                tx.execute("DELETE FROM retries", ())?;
+                {
+                    let mut stmt = tx.prepare(
+                        "INSERT INTO retries (appointment_id, offset_secs, start, attempts, given_up)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                    )?;
+                    for ((id, offset, start), attempts, given_up) in rows {
+                        stmt.execute(params![id, offset, start, attempts, given_up])?;
+                    }
+                }
+                tx.commit()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rebuild a reminders table from an older version, whose primary key left out the start
+/// so that only one start could be stored per reminder. `has_start` is whether it has the
+/// start column at all.
+fn migrate_start_key(conn: &Connection, has_start: bool) -> Result<()> {
+    let start = if has_start { "IFNULL(start, '')" } else { "''" };
+    conn.execute_batch(&format!(
+        "BEGIN;
+        ALTER TABLE reminders RENAME TO reminders_old;
+        CREATE TABLE reminders (
+            appointment_id INTEGER NOT NULL,
+            offset_secs INTEGER NOT NULL,
+            sent_at TEXT NOT NULL,
+            start TEXT NOT NULL DEFAULT '',
+            PRIMARY KEY (appointment_id, offset_secs, start)
+        );
+        INSERT INTO reminders (appointment_id, offset_secs, sent_at, start)
+            SELECT appointment_id, offset_secs, sent_at, {start} FROM reminders_old;
+        DROP TABLE reminders_old;
+        COMMIT;"
+    ))?;
+    Ok(())
+}
+
+/// The start of a reminder read from SQLite, where an empty one means it isn't known.
+fn start_from_row(start: String) -> Option<String> {
+    Some(start).filter(|start| !start.is_empty())
+}
+
+/// Where the text store keeps the per-customer counts.
+fn counts_path(path: &Path) -> PathBuf {
+    sidecar_path(path, ".counts")
+}
+
+/// Where the text store keeps the channels of partly sent reminders.
+fn channels_path(path: &Path) -> PathBuf {
+    sidecar_path(path, ".channels")
+}
+
+/// Where the text store keeps the reminders waiting to be retried.
+fn retries_path(path: &Path) -> PathBuf {
+    sidecar_path(path, ".retries")
+}
+
+/// `path` with `suffix` added to the end of it.
+fn sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Load the sent reminders, and when they were sent, from the text file.
+///
+/// Each line is `<appointment id>,<offset seconds>,<start>,<sent at>`, with the time it was
+/// sent in RFC 3339. Lines from older versions leave out when it was sent, the start as well,
+/// or only contain the appointment ID; those are loaded with `legacy_offset`.
+///
+/// Blank lines are ignored, and lines that can't be parsed are skipped with a warning,
+/// so a corrupt file means resending some reminders rather than not starting at all.
+fn load_text(path: &Path, legacy_offset: u64) -> Result<(HashSet<ReminderKey>, SentTimes)> {
+    let text = fs::read_to_string(path)?;
+    let mut reminders = HashSet::new();
+    let mut sent_at = SentTimes::new();
+    let lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(
+            |(index, line)| match parse_line(line.trim(), legacy_offset) {
+                Some(key) => Some(key),
+                None => {
+                    warn!(
+                        "Skipping unreadable line {} in {}: {line:?}",
+                        index + 1,
+                        path.display()
+                    );
+                    None
+                }
+            },
+        );
+    for (key, sent) in lines {
+        if let Some(sent) = sent {
+            sent_at.insert(key.clone(), sent);
+        }
+        reminders.insert(key);
+    }
+    Ok((reminders, sent_at))
+}
+
+/// Parse a single line of the text file.
+fn parse_line(line: &str, legacy_offset: u64) -> Option<(ReminderKey, Option<DateTime<Utc>>)> {
+    let mut parts = line.splitn(4, ',');
+    let id = parts.next()?.parse().ok()?;
+    let offset = match parts.next() {
+        Some(offset) => offset.parse().ok()?,
+        None => legacy_offset,
+    };
+    let start = parts
+        .next()
+        .filter(|start| !start.is_empty())
+        .map(String::from);
+    let sent = match parts.next() {
+        Some(sent) => Some(DateTime::parse_from_rfc3339(sent).ok()?.to_utc()),
+        None => None,
+    };
+    Some(((id, offset, start), sent))
+}
+
+/// Write the file by writing to a temporary file next to it and renaming it into place.
+///
+/// The rename is atomic, so the file is never left partially written.
+fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_text_skips_malformed_lines() {
+        let path = std::env::temp_dir().join("ea_reminders_load_text_test.txt");
+        fs::write(&path, "1,3600\n\n2\nabc\n3,x\n4,60,2024-01-11 12:00:00\n").unwrap();
+        let (reminders, _) = load_text(&path, 100).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            reminders,
+            HashSet::from([
+                (1, 3600, None),
+                (2, 100, None),
+                (4, 60, Some(String::from("2024-01-11 12:00:00"))),
+            ])
+        );
+    }
+
+    #[test]
+    fn text_store_round_trips() {
+        let path = std::env::temp_dir().join("ea_reminders_round_trip_test.txt");
+        let contents = "1,3600\n2,100,2024-01-11 12:00:00\n3,60,,2024-01-10T09:00:00+00:00\n\
+            10,60,2024-01-12 09:30:00,2024-01-11T09:30:00+00:00";
+        fs::write(&path, contents).unwrap();
+        let mut store = Store::Text {
+            path: path.clone(),
+            legacy_offset: 100,
+        };
+        let (reminders, sent_at) = store.load().unwrap();
+        assert_eq!(sent_at.len(), 2);
+        store.save(&reminders, &sent_at).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved, contents);
+    }
+
+    #[test]
+    fn sqlite_store_keeps_each_start() {
+        let path = std::env::temp_dir().join("ea_reminders_sqlite_start_test.db");
+        let _ = fs::remove_file(&path);
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE reminders (
+                appointment_id INTEGER NOT NULL,
+                offset_secs INTEGER NOT NULL,
+                sent_at TEXT NOT NULL,
+                start TEXT,
+                PRIMARY KEY (appointment_id, offset_secs)
+            );
+            INSERT INTO reminders VALUES (1, 60, '2024-01-10T09:00:00+00:00', NULL);
+            INSERT INTO reminders VALUES (2, 60, '2024-01-10T09:00:00+00:00', '2024-01-11 12:00:00');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut store = Store::open(StoreKind::Sqlite, Some(&path), 0).unwrap();
+        let (mut reminders, sent_at) = store.load().unwrap();
+        assert_eq!(
+            reminders,
+            HashSet::from([
+                (1, 60, None),
+                (2, 60, Some(String::from("2024-01-11 12:00:00")))
+            ])
+        );
+        reminders.insert((2, 60, Some(String::from("2024-01-12 12:00:00"))));
+        store.save(&reminders, &sent_at).unwrap();
+        store.save(&reminders, &sent_at).unwrap();
+        let (loaded, _) = store.load().unwrap();
+        drop(store);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, reminders);
+    }
+
+    #[test]
+    fn remembers_channels_until_sent() {
+        let path = std::env::temp_dir().join("ea_reminders_channels_test.txt");
+        let store = || Store::Text {
+            path: path.clone(),
+            legacy_offset: 0,
+        };
+        let key = (1, 3600, Some(String::from("2024-01-11 12:00:00")));
+        let mut reminders = Persisted::load(store()).unwrap();
+        reminders.mark_channel(key.clone(), "email");
+        reminders.flush().unwrap();
+
+        let mut reminders = Persisted::load(store()).unwrap();
+        assert!(reminders.sent_through(&key, "email"));
+        assert!(!reminders.sent_through(&key, "Telegram"));
+        assert!(!reminders.contains(&key));
+        reminders.mark(key.clone(), Utc::now());
+        assert!(!reminders.sent_through(&key, "email"));
+        reminders.flush().unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(channels_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn retries_round_trip() {
+        let path = std::env::temp_dir().join("ea_reminders_retries_test.txt");
+        let mut store = Store::Text {
+            path: path.clone(),
+            legacy_offset: 0,
+        };
+        let key = |id| (id, 3600, Some(String::from("2024-01-11 12:00:00")));
+        let mut retries = RetryQueue::default();
+        retries.record(&[key(1)], 2);
+        retries.record(&[key(1), key(2)], 2);
+        store.save_retries(&retries).unwrap();
+        let loaded = store.load_retries().unwrap();
+        fs::remove_file(retries_path(&path)).unwrap();
+        assert_eq!(loaded, retries);
+        assert_eq!(loaded.gave_up(&key(1)), Some(2));
+    }
+
+    #[test]
+    fn send_counts_reset_each_day() {
+        let monday = NaiveDate::from_ymd_opt(2024, 1, 8).unwrap();
+        let tuesday = monday.succ_opt().unwrap();
+        let mut counts = SendCounts::default();
+        counts.add("1", monday);
+        counts.add("1", monday);
+        assert_eq!(counts.get("1", monday), 2);
+        assert_eq!(counts.get("1", tuesday), 0);
+        counts.add("2", tuesday);
+        assert_eq!(counts.get("1", tuesday), 0);
+        assert_eq!(counts.get("2", tuesday), 1);
+    }
+
+    #[test]
+    fn send_counts_round_trip_ids_and_emails() {
+        let path = std::env::temp_dir().join("ea_reminders_send_counts_test.db");
+        let _ = fs::remove_file(&path);
+        let monday = NaiveDate::from_ymd_opt(2024, 1, 8).unwrap();
+        let mut counts = SendCounts::default();
+        counts.add("10", monday);
+        counts.add("jane@example.com", monday);
+        let mut store = Store::open(StoreKind::Sqlite, Some(&path), 0).unwrap();
+        store.save_send_counts(&counts).unwrap();
+        let loaded = store.load_send_counts().unwrap();
+        drop(store);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, counts);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    config::Config,
+    email::{self, Mailer},
+    instance_suffix, CheckReport,
+};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use itertools::Itertools;
+use log::info;
+use std::collections::BTreeSet;
+
+/// Counts of what happened to an instance's reminders since the last daily summary.
+///
+/// Appointments are counted once each, however many times they were retried.
+#[derive(Debug)]
+pub struct DailySummary {
+    sent: BTreeSet<u32>,
+    failed: BTreeSet<u32>,
+    failed_checks: u32,
+    /// The local date the last summary was sent, or the first one is due after.
+    last_sent_on: NaiveDate,
+}
+
+impl DailySummary {
+    /// Start tracking, with the first summary due at the next `admin_summary_time`.
+    pub fn new(config: &Config, now: DateTime<Utc>) -> Self {
+        let local = now.with_timezone(&config.timezone);
+        let last_sent_on = if local.time() >= config.admin_summary_time {
+            local.date_naive()
+        } else {
+            local.date_naive().pred_opt().unwrap_or(NaiveDate::MIN)
+        };
+        Self {
+            sent: BTreeSet::new(),
+            failed: BTreeSet::new(),
+            failed_checks: 0,
+            last_sent_on,
+        }
+    }
+
+    /// Add the results of a check.
+    pub fn record(&mut self, report: &CheckReport) {
+        self.sent.extend(&report.sent);
+        self.failed.extend(&report.failed);
+    }
+
+    /// Count a check that couldn't be completed at all.
+    pub fn record_failed_check(&mut self) {
+        self.failed_checks += 1;
+    }
+
+    /// Whether an `admin_email` is set and today's summary hasn't been sent yet.
+    pub fn is_due(&self, config: &Config, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&config.timezone);
+        config.admin_email.is_some()
+            && local.date_naive() > self.last_sent_on
+            && local.time() >= config.admin_summary_time
+    }
+
+    /// Email the summary to the `admin_email` and start counting again.
+    ///
+    /// If `dry_run` is set, the summary is logged instead of sent.
+    pub fn send(
+        &mut self,
+        config: &Config,
+        mailer: &Mailer,
+        now: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let Some(admin_email) = &config.admin_email else {
+            return Ok(());
+        };
+        let subject = format!(
+            "Reminders summary{}: {} sent, {} failed",
+            instance_suffix(config),
+            self.sent.len(),
+            self.failed.len()
+        );
+        let body = self.body();
+        if dry_run {
+            info!("Dry run: would send summary to {admin_email}:\n{body}");
+        } else {
+            email::send_plain_email(mailer, config, admin_email, &subject, body)?;
+            info!("Sent the daily summary to {admin_email}");
+        }
+        let today = now.with_timezone(&config.timezone).date_naive();
+        *self = Self {
+            sent: BTreeSet::new(),
+            failed: BTreeSet::new(),
+            failed_checks: 0,
+            last_sent_on: today,
+        };
+        Ok(())
+    }
+
+    fn body(&self) -> String {
+        let ids = |ids: &BTreeSet<u32>| {
+            if ids.is_empty() {
+                String::from("none")
+            } else {
+                ids.iter().map(|id| format!("#{id}")).join(", ")
+            }
+        };
+        format!(
+            "Since the last summary:\n\n\
+            Appointments sent reminders: {}\n\
+            Appointments whose reminders failed: {}\n\
+            Checks that failed: {}\n\n\
+            Sent for appointments: {}\n\
+            Failed for appointments: {}\n",
+            self.sent.len(),
+            self.failed.len(),
+            self.failed_checks,
+            ids(&self.sent),
+            ids(&self.failed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::test_with(
+            r#"
+            timezone = "UTC"
+            admin_email = "admin@example.com"
+            admin_summary_time = "08:00"
+            "#,
+        )
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn due_once_a_day_after_the_time() {
+        let config = config();
+        let mut summary = DailySummary::new(&config, at("2024-01-10T07:00:00Z"));
+        assert!(!summary.is_due(&config, at("2024-01-10T07:59:00Z")));
+        assert!(summary.is_due(&config, at("2024-01-10T08:00:00Z")));
+        summary
+            .send(
+                &config,
+                &Mailer::new(&config).unwrap(),
+                at("2024-01-10T08:00:00Z"),
+                true,
+            )
+            .unwrap();
+        assert!(!summary.is_due(&config, at("2024-01-10T20:00:00Z")));
+        assert!(summary.is_due(&config, at("2024-01-11T08:30:00Z")));
+    }
+
+    #[test]
+    fn not_due_on_the_day_it_starts_after_the_time() {
+        let config = config();
+        let summary = DailySummary::new(&config, at("2024-01-10T09:00:00Z"));
+        assert!(!summary.is_due(&config, at("2024-01-10T23:00:00Z")));
+        assert!(summary.is_due(&config, at("2024-01-11T08:00:00Z")));
+    }
+
+    #[test]
+    fn counts_each_appointment_once() {
+        let config = config();
+        let mut summary = DailySummary::new(&config, at("2024-01-10T07:00:00Z"));
+        let failed = CheckReport {
+            failed: vec![1],
+            ..CheckReport::default()
+        };
+        summary.record(&failed);
+        summary.record(&failed);
+        summary.record(&CheckReport {
+            sent: vec![2],
+            failed: vec![1],
+            ..CheckReport::default()
+        });
+        assert_eq!(summary.failed.len(), 1);
+        assert!(summary
+            .body()
+            .contains("Sent for appointments: #2\nFailed for appointments: #1\n"));
+    }
+}
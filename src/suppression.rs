@@ -0,0 +1,117 @@
+use crate::api::CustomerInfo;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+/// Customers that have asked not to get reminders.
+#[derive(Debug, Default)]
+pub struct Suppressions {
+    /// Lowercased email addresses.
+    emails: HashSet<String>,
+    customer_ids: HashSet<u32>,
+}
+
+impl Suppressions {
+    /// Load the suppression list from a file.
+    ///
+    /// Each line is either an email address or a customer ID. Blank lines and lines
+    /// starting with `#` are ignored. A file that doesn't exist yet is an empty list.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(anyhow!(
+                    "Could not read suppression file {}: {e}",
+                    path.display()
+                ))
+            }
+        };
+        let mut suppressions = Self::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.parse() {
+                Ok(id) => {
+                    suppressions.customer_ids.insert(id);
+                }
+                Err(_) => {
+                    suppressions.emails.insert(line.to_lowercase());
+                }
+            }
+        }
+        Ok(suppressions)
+    }
+
+    /// Whether the customer is on the list, by ID or by email, ignoring case.
+    pub fn contains(&self, customer: &CustomerInfo) -> bool {
+        self.customer_ids.contains(&customer.id)
+            || self.emails.contains(&customer.email.to_lowercase())
+    }
+}
+
+/// Add an email address to the end of the suppression file, unless it's already there.
+///
+/// The file is created if it doesn't exist. If it was edited by hand and doesn't end in
+/// a newline, one is added first so the address goes on its own line.
+pub fn append(path: &Path, email: &str) -> Result<()> {
+    let email = email.trim().to_lowercase();
+    if Suppressions::load(path)?.emails.contains(&email) {
+        return Ok(());
+    }
+    let missing_newline = match fs::read(path) {
+        Ok(bytes) => bytes.last().is_some_and(|&byte| byte != b'\n'),
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) => {
+            return Err(anyhow!(
+                "Could not read suppression file {}: {e}",
+                path.display()
+            ))
+        }
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Could not open suppression file {}: {e}", path.display()))?;
+    if missing_newline {
+        writeln!(file)?;
+    }
+    writeln!(file, "{email}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_file_is_an_empty_list() {
+        let path = std::env::temp_dir().join("ea_reminders_missing_suppressions.txt");
+        let _ = fs::remove_file(&path);
+        assert!(Suppressions::load(&path).unwrap().emails.is_empty());
+        append(&path, "Jane@Example.com").unwrap();
+        append(&path, "jane@example.com").unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(text, "jane@example.com\n");
+    }
+
+    #[test]
+    fn appends_on_a_new_line() {
+        let path = std::env::temp_dir().join("ea_reminders_no_newline_suppressions.txt");
+        fs::write(&path, "a@x.com").unwrap();
+        append(&path, "b@y.com").unwrap();
+        let suppressions = Suppressions::load(&path).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(text, "a@x.com\nb@y.com\n");
+        assert!(suppressions.emails.contains("a@x.com"));
+        assert!(suppressions.emails.contains("b@y.com"));
+    }
+}
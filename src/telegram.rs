@@ -0,0 +1,105 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    config::Config,
+    notify::Notifier,
+    template::{render_template, AppointmentValues},
+};
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+const TELEGRAM_API: &str = "https://api.telegram.org";
+
+/// Telegram bot token and the chat to post to.
+pub struct Telegram<'a> {
+    pub bot_token: &'a str,
+    pub chat_id: &'a str,
+}
+
+/// The parts of a Bot API response that say whether the request worked.
+#[derive(Debug, Deserialize)]
+struct TelegramResponse {
+    ok: bool,
+    description: Option<String>,
+}
+
+/// Post a message to the chat through the Telegram Bot API.
+///
+/// Errors leave out the request URL, since it contains the bot token.
+pub fn send_telegram(client: &Client, telegram: &Telegram, text: &str) -> Result<()> {
+    let resp = client
+        .post(format!(
+            "{TELEGRAM_API}/bot{}/sendMessage",
+            telegram.bot_token
+        ))
+        .json(&json!({ "chat_id": telegram.chat_id, "text": text }))
+        .send()
+        .map_err(reqwest::Error::without_url)?;
+    let status = resp.status();
+    let body: Option<TelegramResponse> = resp.json().ok();
+    match body {
+        Some(TelegramResponse { ok: true, .. }) if status.is_success() => {
+            debug!("Telegram notification sent");
+            Ok(())
+        }
+        Some(TelegramResponse {
+            description: Some(description),
+            ..
+        }) => Err(anyhow!(
+            "Got status {} from Telegram: {description}",
+            status.as_u16()
+        )),
+        _ => Err(anyhow!("Got status {} from Telegram", status.as_u16())),
+    }
+}
+
+/// Posts each reminder to a Telegram chat, such as for staff.
+pub struct TelegramNotifier<'a> {
+    client: &'a Client,
+    config: &'a Config,
+    telegram: Telegram<'a>,
+    dry_run: bool,
+}
+
+impl<'a> TelegramNotifier<'a> {
+    /// If `dry_run` is set, messages are logged instead of posted.
+    pub fn new(
+        client: &'a Client,
+        config: &'a Config,
+        telegram: Telegram<'a>,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            client,
+            config,
+            telegram,
+            dry_run,
+        }
+    }
+}
+
+impl Notifier for TelegramNotifier<'_> {
+    fn name(&self) -> &'static str {
+        "Telegram"
+    }
+
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+        let values = AppointmentValues::new(appointment, customer, self.config)?;
+        let template = self
+            .config
+            .telegram_message
+            .as_ref()
+            .unwrap_or(&self.config.email_body);
+        let text = render_template(template, customer, &values);
+        if self.dry_run {
+            info!(
+                "Dry run: would post to Telegram chat {}:\n{text}",
+                self.telegram.chat_id
+            );
+            return Ok(());
+        }
+        send_telegram(self.client, &self.telegram, &text)
+    }
+}
@@ -0,0 +1,423 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    config::Config,
+    unsubscribe,
+};
+use anyhow::Result;
+use chrono::TimeDelta;
+use handlebars::{Handlebars, Template};
+use reqwest::Url;
+use serde::Serialize;
+
+/// Placeholders that can be used in templates, without the surrounding `%`.
+///
+/// `APPOINTMENTS` is only filled in for digest emails, and `UNSUBSCRIBE_URL` is empty
+/// unless `unsubscribe_url_template` is set. `APPOINTMENT_END` and
+/// `APPOINTMENT_DURATION` are empty if the API didn't return an end time, `LOCATION`
+/// and `MAPS_URL` are empty if the appointment has no location, and `NOTES` is empty if it
+/// has no notes.
+pub const PLACEHOLDERS: &[&str] = &[
+    "APPOINTMENT_DATETIME",
+    "APPOINTMENT_END",
+    "APPOINTMENT_DURATION",
+    "ADD_TO_CALENDAR_URL",
+    "OUTLOOK_CALENDAR_URL",
+    "RESCHEDULE_URL",
+    "LOCATION",
+    "MAPS_URL",
+    "NOTES",
+    "SERVICE_NAME",
+    "PROVIDER_NAME",
+    "FIRST_NAME",
+    "LAST_NAME",
+    "UNSUBSCRIBE_URL",
+    "APPOINTMENTS",
+];
+
+/// Values for the appointment placeholders, formatted to show to customers.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AppointmentValues {
+    pub datetime: String,
+    pub end: String,
+    pub duration: String,
+    /// Link that opens the event in Google Calendar, ready to save.
+    pub add_to_calendar_url: String,
+    /// Link that opens the event in Outlook on the web, ready to save.
+    pub outlook_calendar_url: String,
+    /// `reschedule_url_template` for the appointment, or empty if it isn't set.
+    pub reschedule_url: String,
+    pub location: String,
+    /// Link that searches Google Maps for the location, or empty if there isn't one.
+    pub maps_url: String,
+    /// The appointment's notes, without the lines added by `write_back_reminder`.
+    pub notes: String,
+    pub service_name: String,
+    pub provider_name: String,
+    /// `unsubscribe_url_template` for the customer, or empty if it isn't set.
+    pub unsubscribe_url: String,
+}
+
+impl AppointmentValues {
+    /// Format the appointment's times with the configured `timezone` and `email_datetime_format`.
+    pub fn new(
+        appointment: &Appointment,
+        customer_info: &CustomerInfo,
+        config: &Config,
+    ) -> Result<Self> {
+        let format = config.email_datetime_format.as_deref();
+        let duration = match appointment.end_date(config.timezone)? {
+            Some(end) => format_duration(end - appointment.start_date(config.timezone)?),
+            None => String::new(),
+        };
+        let (add_to_calendar_url, outlook_calendar_url) = calendar_urls(appointment, config)?;
+        let location = appointment.location().unwrap_or_default();
+        let maps_url = if location.is_empty() {
+            String::new()
+        } else {
+            Url::parse_with_params(
+                "https://www.google.com/maps/search/",
+                [("api", "1"), ("query", location)],
+            )?
+            .into()
+        };
+        Ok(Self {
+            datetime: appointment.display_start(config.timezone, format)?,
+            end: appointment
+                .display_end(config.timezone, format)?
+                .unwrap_or_default(),
+            duration,
+            add_to_calendar_url,
+            outlook_calendar_url,
+            reschedule_url: config
+                .reschedule_url_template
+                .as_ref()
+                .map(|template| template.replace("%APPOINTMENT_ID%", &appointment.id.to_string()))
+                .unwrap_or_default(),
+            location: location.to_owned(),
+            maps_url,
+            notes: appointment.customer_notes().unwrap_or_default(),
+            service_name: appointment.service_name.clone().unwrap_or_default(),
+            provider_name: appointment.provider_name.clone().unwrap_or_default(),
+            unsubscribe_url: unsubscribe::unsubscribe_url(config, &customer_info.email),
+        })
+    }
+}
+
+/// Build the Google Calendar and Outlook links to add the appointment to a calendar.
+///
+/// The event is named after the service, if it was fetched, and lasts until the appointment's
+/// end, or for `ics_duration_minutes` if the API didn't return one.
+fn calendar_urls(appointment: &Appointment, config: &Config) -> Result<(String, String)> {
+    let start = appointment.start_date(config.timezone)?;
+    let end = appointment
+        .end_date(config.timezone)?
+        .unwrap_or(start + TimeDelta::minutes(config.ics_duration_minutes));
+    let title = appointment.service_name.as_deref().unwrap_or("Appointment");
+    let details = appointment
+        .provider_name
+        .as_ref()
+        .map(|provider| format!("With {provider}"))
+        .unwrap_or_default();
+    let google = Url::parse_with_params(
+        "https://calendar.google.com/calendar/render",
+        [
+            ("action", "TEMPLATE"),
+            ("text", title),
+            (
+                "dates",
+                &format!(
+                    "{}/{}",
+                    start.format("%Y%m%dT%H%M%SZ"),
+                    end.format("%Y%m%dT%H%M%SZ")
+                ),
+            ),
+            ("details", &details),
+        ],
+    )?;
+    let outlook = Url::parse_with_params(
+        "https://outlook.live.com/calendar/0/deeplink/compose",
+        [
+            ("path", "/calendar/action/compose"),
+            ("rru", "addevent"),
+            ("subject", title),
+            ("startdt", &start.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            ("enddt", &end.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            ("body", &details),
+        ],
+    )?;
+    Ok((google.into(), outlook.into()))
+}
+
+/// Substitute the appointment and customer placeholders in a template.
+pub fn render_template(
+    template: &str,
+    customer_info: &CustomerInfo,
+    appointment: &AppointmentValues,
+) -> String {
+    fill_placeholders(template, customer_info, appointment, str::to_owned)
+}
+
+/// Substitute the placeholders like `render_template`, with every value HTML-escaped, for
+/// HTML emails.
+///
+/// Customers enter their own names and notes when booking, so they can't be trusted as HTML.
+pub fn render_html_template(
+    template: &str,
+    customer_info: &CustomerInfo,
+    appointment: &AppointmentValues,
+) -> String {
+    fill_placeholders(
+        template,
+        customer_info,
+        appointment,
+        handlebars::html_escape,
+    )
+}
+
+/// Substitute the placeholders in a template, passing each value through `escape`.
+fn fill_placeholders(
+    template: &str,
+    customer_info: &CustomerInfo,
+    appointment: &AppointmentValues,
+    escape: fn(&str) -> String,
+) -> String {
+    template
+        .replace("%APPOINTMENT_DATETIME%", &escape(&appointment.datetime))
+        .replace("%APPOINTMENT_END%", &escape(&appointment.end))
+        .replace("%APPOINTMENT_DURATION%", &escape(&appointment.duration))
+        .replace(
+            "%ADD_TO_CALENDAR_URL%",
+            &escape(&appointment.add_to_calendar_url),
+        )
+        .replace(
+            "%OUTLOOK_CALENDAR_URL%",
+            &escape(&appointment.outlook_calendar_url),
+        )
+        .replace("%RESCHEDULE_URL%", &escape(&appointment.reschedule_url))
+        .replace("%LOCATION%", &escape(&appointment.location))
+        .replace("%MAPS_URL%", &escape(&appointment.maps_url))
+        .replace("%NOTES%", &escape(&appointment.notes))
+        .replace("%SERVICE_NAME%", &escape(&appointment.service_name))
+        .replace("%PROVIDER_NAME%", &escape(&appointment.provider_name))
+        .replace("%FIRST_NAME%", &escape(&customer_info.first_name))
+        .replace("%LAST_NAME%", &escape(&customer_info.last_name))
+        .replace("%UNSUBSCRIBE_URL%", &escape(&appointment.unsubscribe_url))
+}
+
+/// Data that Handlebars templates are rendered with.
+///
+/// The appointment's values are available at the top level, named like the placeholders
+/// but in lowercase, such as `{{datetime}}` and `{{service_name}}`.
+#[derive(Debug, Serialize)]
+pub struct TemplateContext<'a> {
+    pub first_name: &'a str,
+    pub last_name: &'a str,
+    pub email: &'a str,
+    /// The appointment the reminder is for; empty for digest emails.
+    #[serde(flatten)]
+    pub appointment: &'a AppointmentValues,
+    /// Every appointment a digest email is for; empty otherwise.
+    pub appointments: &'a [AppointmentValues],
+}
+
+impl<'a> TemplateContext<'a> {
+    pub fn new(
+        customer_info: &'a CustomerInfo,
+        appointment: &'a AppointmentValues,
+        appointments: &'a [AppointmentValues],
+    ) -> Self {
+        Self {
+            first_name: &customer_info.first_name,
+            last_name: &customer_info.last_name,
+            email: &customer_info.email,
+            appointment,
+            appointments,
+        }
+    }
+}
+
+/// Render a Handlebars template.
+///
+/// Values are HTML-escaped only if `escape_html` is set, so plain text stays as-is.
+pub fn render_handlebars(
+    template: &str,
+    context: &TemplateContext,
+    escape_html: bool,
+) -> Result<String> {
+    let mut handlebars = Handlebars::new();
+    if !escape_html {
+        handlebars.register_escape_fn(handlebars::no_escape);
+    }
+    Ok(handlebars.render_template(template, context)?)
+}
+
+/// Check that a Handlebars template can be compiled.
+pub fn check_handlebars(template: &str) -> Result<()> {
+    Template::compile(template)?;
+    Ok(())
+}
+
+/// Format a duration for customers, like "1 hour 30 minutes".
+fn format_duration(duration: TimeDelta) -> String {
+    let plural = |n: i64, unit: &str| {
+        if n == 1 {
+            format!("{n} {unit}")
+        } else {
+            format!("{n} {unit}s")
+        }
+    };
+    let minutes = duration.num_minutes().max(0);
+    match (minutes / 60, minutes % 60) {
+        (0, m) => plural(m, "minute"),
+        (h, 0) => plural(h, "hour"),
+        (h, m) => format!("{} {}", plural(h, "hour"), plural(m, "minute")),
+    }
+}
+
+/// Convert an HTML document to plain text by stripping its tags.
+///
+/// Line-breaking tags become newlines and the common entities are decoded.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut tag: Option<String> = None;
+    for c in html.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some(String::new()),
+            (None, c) => text.push(c),
+            (Some(name), '>') => {
+                let name = name
+                    .trim_start_matches('/')
+                    .split(|c: char| c.is_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_lowercase();
+                if matches!(
+                    name.as_str(),
+                    "br" | "p" | "div" | "li" | "tr" | "h1" | "h2" | "h3"
+                ) {
+                    text.push('\n');
+                }
+                tag = None;
+            }
+            (Some(name), c) => name.push(c),
+        }
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&#x60;", "`")
+        .replace("&#x3D;", "=")
+        .replace("&amp;", "&")
+}
+
+/// Find the `%PLACEHOLDER%`-style names in a template that aren't known placeholders.
+///
+/// Only uppercase names are considered, so a lone `%` in the text isn't flagged.
+pub fn unknown_placeholders(template: &str) -> Vec<&str> {
+    let parts = template.split('%').collect::<Vec<_>>();
+    // only the parts with a `%` on both sides can be placeholders
+    parts
+        .iter()
+        .skip(1)
+        .take(parts.len().saturating_sub(2))
+        .copied()
+        .filter(|name| !name.is_empty())
+        .filter(|name| name.chars().all(|c| c.is_ascii_uppercase() || c == '_'))
+        .filter(|name| !PLACEHOLDERS.contains(name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(TimeDelta::minutes(30)), "30 minutes");
+        assert_eq!(format_duration(TimeDelta::minutes(60)), "1 hour");
+        assert_eq!(format_duration(TimeDelta::minutes(61)), "1 hour 1 minute");
+        assert_eq!(
+            format_duration(TimeDelta::minutes(150)),
+            "2 hours 30 minutes"
+        );
+    }
+
+    #[test]
+    fn builds_calendar_urls() {
+        let config = Config::test_with(
+            r#"
+            timezone = "America/New_York"
+            "#,
+        );
+        let mut appointment: Appointment = serde_json::from_value(serde_json::json!({
+            "id": 1, "start": "2024-01-11 12:00:00", "end": "2024-01-11 13:30:00", "customerId": 1,
+        }))
+        .unwrap();
+        appointment.service_name = Some(String::from("Check-up & cleaning"));
+        let customer = CustomerInfo {
+            id: 1,
+            first_name: String::from("Jane"),
+            last_name: String::from("Doe"),
+            email: String::from("jane@example.com"),
+            phone: None,
+            fields: Default::default(),
+        };
+        let (google, outlook) = calendar_urls(&appointment, &config).unwrap();
+        assert_eq!(
+            google,
+            "https://calendar.google.com/calendar/render?action=TEMPLATE\
+            &text=Check-up+%26+cleaning&dates=20240111T170000Z%2F20240111T183000Z&details="
+        );
+        assert!(
+            outlook.contains("&startdt=2024-01-11T17%3A00%3A00Z&enddt=2024-01-11T18%3A30%3A00Z")
+        );
+        let values = AppointmentValues::new(&appointment, &customer, &config).unwrap();
+        assert_eq!(values.location, "");
+        assert_eq!(values.maps_url, "");
+        appointment.location = Some(String::from("Main St Branch"));
+        let values = AppointmentValues::new(&appointment, &customer, &config).unwrap();
+        assert_eq!(
+            values.maps_url,
+            "https://www.google.com/maps/search/?api=1&query=Main+St+Branch"
+        );
+    }
+
+    #[test]
+    fn fills_in_escaped_values_for_html() {
+        let config = Config::test_default();
+        let appointment: Appointment = serde_json::from_value(serde_json::json!({
+            "id": 1, "start": "2024-01-11 12:00:00", "customerId": 1,
+            "notes": "Arrive 15 min early & bring <ID>\nReminder sent on 2024-01-10",
+        }))
+        .unwrap();
+        let customer = CustomerInfo {
+            id: 1,
+            first_name: String::from("<a href=\"https://example.com\">Jane</a>"),
+            last_name: String::from("Doe"),
+            email: String::from("jane@example.com"),
+            phone: None,
+            fields: Default::default(),
+        };
+        let values = AppointmentValues::new(&appointment, &customer, &config).unwrap();
+        assert_eq!(
+            render_template("Notes: %NOTES%", &customer, &values),
+            "Notes: Arrive 15 min early & bring <ID>"
+        );
+        assert_eq!(
+            render_html_template("Notes: %NOTES%", &customer, &values),
+            "Notes: Arrive 15 min early &amp; bring &lt;ID&gt;"
+        );
+        let html = render_html_template("Hi %FIRST_NAME%", &customer, &values);
+        assert_eq!(
+            html,
+            "Hi &lt;a href&#x3D;&quot;https://example.com&quot;&gt;Jane&lt;/a&gt;"
+        );
+        assert_eq!(
+            strip_html(&html),
+            "Hi <a href=\"https://example.com\">Jane</a>"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{config::Config, suppression};
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use log::info;
+use sha2::Sha256;
+use std::path::PathBuf;
+use url::form_urlencoded;
+
+/// Fill in the `unsubscribe_url_template` for the email address, or return an empty
+/// string if it isn't set.
+pub fn unsubscribe_url(config: &Config, email: &str) -> String {
+    config
+        .unsubscribe_url_template
+        .as_ref()
+        .map(|template| fill_in(template, config.unsubscribe_secret.as_deref(), email))
+        .unwrap_or_default()
+}
+
+/// Replace `%EMAIL%` with the address, and `%TOKEN%` with its signature with `secret`,
+/// both URL-encoded. `%TOKEN%` is left empty without a secret.
+fn fill_in(template: &str, secret: Option<&str>, email: &str) -> String {
+    let encode =
+        |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+    let token = secret
+        .map(|secret| token(secret, email))
+        .unwrap_or_default();
+    template
+        .replace("%EMAIL%", &encode(email))
+        .replace("%TOKEN%", &token)
+}
+
+/// The signature that proves an unsubscribe link was sent by this app: the hex-encoded
+/// HMAC-SHA256 of the lowercased email address.
+fn token(secret: &str, email: &str) -> String {
+    mac(secret, email)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The HMAC-SHA256 of the lowercased email address, keyed with `secret`.
+fn mac(secret: &str, email: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(email.to_lowercase().as_bytes());
+    mac
+}
+
+/// Decode a hex string, or return `None` if it isn't valid hex.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Handles the unsubscribe links, by adding their email addresses to the suppression file.
+pub struct Unsubscriber {
+    secret: String,
+    suppression_file: PathBuf,
+}
+
+impl Unsubscriber {
+    pub fn new(secret: String, suppression_file: PathBuf) -> Self {
+        Self {
+            secret,
+            suppression_file,
+        }
+    }
+
+    /// Whether the token is the one sent in the email's unsubscribe link.
+    pub fn is_valid(&self, email: &str, token: &str) -> bool {
+        // verified in constant time, so the token can't be guessed byte by byte
+        decode_hex(token).is_some_and(|token| mac(&self.secret, email).verify_slice(&token).is_ok())
+    }
+
+    /// Add the email address to the suppression file, so it gets no more reminders.
+    pub fn unsubscribe(&self, email: &str) -> Result<()> {
+        suppression::append(&self.suppression_file, email)?;
+        info!("Unsubscribed {email}, adding them to the suppression file");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_unsubscribe_links() {
+        // test case 2 from RFC 4231
+        assert_eq!(
+            token("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let url = fill_in(
+            "https://example.com/unsubscribe?email=%EMAIL%&token=%TOKEN%",
+            Some("secret"),
+            "Jane+Doe@example.com",
+        );
+        let token = token("secret", "jane+doe@example.com");
+        assert_eq!(
+            url,
+            format!("https://example.com/unsubscribe?email=Jane%2BDoe%40example.com&token={token}")
+        );
+        let unsubscriber = Unsubscriber::new(String::from("secret"), PathBuf::new());
+        assert!(unsubscriber.is_valid("jane+doe@example.com", &token));
+        assert!(!unsubscriber.is_valid("john@example.com", &token));
+        assert!(!unsubscriber.is_valid("jane+doe@example.com", ""));
+        assert!(!unsubscriber.is_valid("jane+doe@example.com", &token[1..]));
+        assert!(unsubscriber.is_valid("jane+doe@example.com", &token.to_uppercase()));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{
+    api::{Appointment, CustomerInfo},
+    notify::Notifier,
+};
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use reqwest::blocking::Client;
+use serde::Serialize;
+
+/// JSON body posted to the webhook for each reminder.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    appointment_id: u32,
+    customer_email: &'a str,
+    first_name: &'a str,
+    last_name: &'a str,
+    start: &'a str,
+}
+
+/// Post the reminder to the webhook, treating any non-2xx response as a failure.
+pub fn send_webhook(
+    client: &Client,
+    url: &str,
+    customer_info: &CustomerInfo,
+    appointment: &Appointment,
+) -> Result<()> {
+    let resp = client
+        .post(url)
+        .json(&Payload {
+            appointment_id: appointment.id,
+            customer_email: &customer_info.email,
+            first_name: &customer_info.first_name,
+            last_name: &customer_info.last_name,
+            start: &appointment.start,
+        })
+        .send()?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Got status {} from webhook",
+            resp.status().as_u16()
+        ));
+    }
+    debug!("Webhook notification sent");
+    Ok(())
+}
+
+/// Posts each reminder to a webhook as JSON.
+pub struct WebhookNotifier<'a> {
+    client: &'a Client,
+    url: &'a str,
+    dry_run: bool,
+}
+
+impl<'a> WebhookNotifier<'a> {
+    /// If `dry_run` is set, reminders are logged instead of posted.
+    pub fn new(client: &'a Client, url: &'a str, dry_run: bool) -> Self {
+        Self {
+            client,
+            url,
+            dry_run,
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier<'_> {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn notify(&self, customer: &CustomerInfo, appointment: &Appointment) -> Result<()> {
+        if self.dry_run {
+            info!(
+                "Dry run: would post appointment #{} to webhook",
+                appointment.id
+            );
+            return Ok(());
+        }
+        send_webhook(self.client, self.url, customer, appointment)
+    }
+}